@@ -6,32 +6,531 @@
  */
 
 use std::convert::TryFrom;
-use quote::ToTokens;
-use syn::{ItemStruct, Field, Ident, Type, Attribute, Visibility};
-use syn::parse::{Parse, ParseBuffer};
+use quote::{quote, quote_spanned, ToTokens};
+use proc_macro2::{Span, TokenStream, Literal};
+use syn::{ItemStruct, Field, Ident, Type, Attribute, Visibility, Generics, Path, Token, LitInt};
+use syn::punctuated::Punctuated;
+use syn::parse::{Parse, ParseBuffer, ParseStream};
+
+use crate::macroparams::{MacroParams, Endianness};
 
 
 static ERR_FORMAT: &str = "Expected format: any_field_name_intSize";
 
+// Traits implemented by the macro based on the declared fields, rather than derived on `raw`
+pub static FIELD_AWARE_DERIVES: &[&str] = &["Debug", "PartialEq", "Hash", "Display", "FromStr"];
+
+// Names of the items generated on every structure, which a field cannot take
+static GENERATED_ITEMS: &[&str] = &["raw", "default", "pprint", "print_scale", "print_line", "variant"];
+
+// Names of the pretty print helpers generated by the 'pp' parameter
+static PPRINT_ITEMS: &[&str] = &["pprint_diff", "pprint_map", "pprint_bits", "pprint_render"];
+
+// Names of the helpers generated on bitfields backed by an array
+static ARRAY_ITEMS: &[&str] = &["read_bits", "write_bits", "read_bytes", "write_bytes"];
+
+// Names of the register accesses generated by the 'bus' parameter
+static BUS_ITEMS: &[&str] = &["read_from", "write_to", "modify_on"];
+
+// Names of the batched field writes generated by the 'update' parameter
+static UPDATE_ITEMS: &[&str] = &["update", "set_fields"];
+
+// Names of the accesses through the enum of the fields, generated by the 'dynamic' parameter
+static DYNAMIC_ITEMS: &[&str] = &["get", "set", "field_by_name", "fields", "diff"];
+
+// Names of the items generated by the 'assert' parameter
+static ASSERT_ITEMS: &[&str] = &["mismatch_report"];
+
+// Names of the items generated by the 'pattern' parameter
+static PATTERN_ITEMS: &[&str] = &["matches", "matches_pattern", "pattern", "from_pattern"];
+
+// Names of the items generated by the 'bytes' parameter
+static BYTES_ITEMS: &[&str] = &["to_be_bytes", "to_le_bytes", "from_be_bytes", "from_le_bytes"];
+
+// Names of the items generated by the 'byte_order' parameter
+static BYTE_ORDER_ITEMS: &[&str] = &["to_bytes", "from_bytes", "from_slice"];
+
 pub struct Strukt
 {
     pub name:       Ident,                  // The name of the structure
-    pub bfields:    Vec<BitField>,          // A vector of all the declared fields 
+    pub span:       Span,                   // The span of the structure name, for error reporting
+    pub bfields:    Vec<BitField>,          // A vector of all the declared fields, reserved ones included
     pub attrs:      Vec<Attribute>,         // A vector of all the declared attributes
     pub vis:        Visibility,             // The visibility modifier of the struct
-    pub map:        Vec<(usize, usize)>,    // A map of the size and accessibility of the bitfields
-                                            //      used to generate the pretty print function
+    pub generics:   Generics,               // The generic parameters and where clause of the struct
 }
 
 impl Strukt
 {
-    pub fn is_large_enough(self: &Self, bitfield_size: usize) -> bool
+    // Fields sharing the bits of another field do not take room in the layout
+    pub fn declared_size(&self) -> usize
+    {
+        self.bfields.iter()
+                    .filter(|x| !x.is_shadow())
+                    .map(|x| x.bsize)
+                    .sum()
+    }
+
+    pub fn is_large_enough(&self, bitfield_size: usize) -> bool
     {
-        let total_size: usize = self.bfields.iter()
-                                            .map(|x| x.bsize)
-                                            .sum();
+        self.declared_size() <= bitfield_size
+    }
+
+    // Remove the field aware traits from the #[derive(...)] attributes of the struct, and return them
+    // The other derived traits are left untouched
+    pub fn take_derives(&mut self) -> syn::Result<Vec<Path>>
+    {
+        let mut taken = Vec::new();
+        let mut attrs = Vec::new();
+
+        for attr in self.attrs.drain(..)
+        {
+            if !attr.path().is_ident("derive")
+            {
+                attrs.push(attr);
+                continue;
+            }
 
-        total_size <= bitfield_size
+            let paths = attr.parse_args_with(Punctuated::<Path, Token![,]>::parse_terminated)?;
+            let (aware, kept): (Vec<Path>, Vec<Path>) = paths.into_iter().partition(is_field_aware);
+
+            taken.extend(aware);
+            if !kept.is_empty()
+            {
+                attrs.push(syn::parse_quote!{ #[derive(#(#kept),*)] });
+            }
+        }
+
+        self.attrs = attrs;
+        Ok(taken)
+    }
+
+    // Statements discarding, in the value `recv` about to be written back, the bits which must not be written back
+    // See Policy::masked_on_write_back()
+    pub fn write_back_tokens(&self, params: &MacroParams, recv: &TokenStream) -> TokenStream
+    {
+        let stores = self.accessors()
+                         .filter(|f| f.policy.masked_on_write_back())
+                         .map(|f|
+                              {
+                                  let cfg = &f.cfg;
+                                  let store = f.store_tokens(params, recv, &f.zero_tokens(params));
+                                  quote!{ #(#cfg)* { #store } }
+                              });
+
+        quote!{ #(#stores)* }
+    }
+
+    // Marker field holding the type and lifetime parameters of the struct, which are not used by `raw`
+    pub fn phantom(&self) -> Option<(TokenStream, TokenStream)>
+    {
+        let lifetimes: Vec<_> = self.generics.lifetimes().map(|l| &l.lifetime).collect();
+        let types: Vec<_> = self.generics.type_params().map(|t| &t.ident).collect();
+
+        if lifetimes.is_empty() && types.is_empty()
+        {
+            return None;
+        }
+
+        Some( (quote!{ _marker: core::marker::PhantomData<(#(&#lifetimes (),)* #(#types,)*)> },
+               quote!{ _marker: core::marker::PhantomData }) )
+    }
+
+    // Expression building the structure from the value of raw
+    pub fn construct_tokens(&self, params: &MacroParams, raw: &TokenStream) -> TokenStream
+    {
+        let raw = params.wrap_tokens(raw);
+
+        match self.phantom()
+        {
+            Some((_, init)) => quote!{ Self { raw: #raw, #init } },
+            None => quote!{ Self { raw: #raw } },
+        }
+    }
+
+    // True if some fields are only present under a #[cfg(...)] attribute
+    // In that case the layout can only be known when compiling the generated code
+    pub fn is_conditional(&self) -> bool
+    {
+        self.bfields.iter().any(|f| f.is_conditional())
+    }
+
+    // Const expression of the size of the bitfield, taking the #[cfg(...)] of each field into account
+    pub fn declared_size_tokens(&self) -> TokenStream
+    {
+        let sizes = self.bfields.iter().filter(|f| !f.is_shadow()).map(|f| f.size_tokens());
+
+        quote!{ (0 #(+ #sizes)*) }
+    }
+
+    // Iterate over the fields for which accessors are implemented
+    pub fn accessors(&self) -> impl Iterator<Item = &BitField>
+    {
+        self.bfields.iter().filter(|f| !f.skip)
+    }
+
+    // Iterate over the fields represented by the generated traits, reports and iterators
+    // Overlays are left out, as they repeat the bits of the field they share
+    pub fn reported(&self) -> impl Iterator<Item = &BitField>
+    {
+        self.accessors().filter(|f| !f.overlay)
+    }
+
+    // Field selecting the variants, if any are declared
+    pub fn variant_tag(&self) -> Option<&BitField>
+    {
+        let tag = self.accessors().find_map(|f| f.variant.as_ref())?;
+        self.accessors().find(|f| tag.tag == f.name && f.variant.is_none())
+    }
+
+    // if Endianness enum is set on Most Significant Bit (MSB)
+    // reverse position of the fields
+    // Fields declared after a conditional field get their position as a const expression
+    pub fn apply_order(&mut self, endianness: &Endianness, bitfield_size: usize)
+    {
+        let mut new_pos = bitfield_size;
+        let mut preceding: Vec<TokenStream> = Vec::new();
+        let mut conditional = false;
+
+        for f in self.bfields.iter_mut().filter(|f| !f.is_shadow())
+        {
+            let size = f.bsize;
+
+            // Fields which are never enabled together may not fit in the bitfield at once
+            // Past a conditional field, the position is given by the const expression below instead
+            if *endianness == Endianness::Msb
+            {
+                new_pos = new_pos.saturating_sub(f.bsize);
+                f.update_pos(new_pos);
+            }
+
+            if conditional
+            {
+                f.pos_expr = match endianness
+                {
+                    Endianness::Lsb => Some(quote!{ (0 #(+ #preceding)*) }),
+                    Endianness::Msb => Some(quote!{ (#bitfield_size - #size #(- #preceding)*) }),
+                };
+            }
+
+            conditional |= f.is_conditional();
+            preceding.push(f.size_tokens());
+        }
+
+        // Fields sharing the bits of a host are placed from its first declared bit
+        for i in 0..self.bfields.len()
+        {
+            let host = match self.bfields[i].host
+            {
+                Some(host) => self.bfields[host].clone(),
+                None => continue,
+            };
+            let f = &mut self.bfields[i];
+
+            let offset = match endianness
+            {
+                Endianness::Lsb => f.offset,
+                Endianness::Msb => host.bsize - f.offset - f.bsize,
+            };
+
+            f.update_pos(host.pos + offset);
+            f.pos_expr = host.pos_expr.as_ref().map(|e| quote!{ (#e + #offset) });
+        }
+    }
+
+    // Range of bits, if any, which are not covered by a declared field
+    // Conditional layouts are checked by the assertions generated in layout_assertions()
+    pub fn unused_bits(&self, endianness: &Endianness, bitfield_size: usize) -> Option<(usize, usize)>
+    {
+        let declared = self.declared_size();
+
+        if declared >= bitfield_size || self.is_conditional()
+        {
+            return None;
+        }
+
+        match endianness
+        {
+            Endianness::Lsb => Some((declared, bitfield_size - 1)),
+            Endianness::Msb => Some((0, bitfield_size - declared - 1)),
+        }
+    }
+
+    // Reject layouts that would not compile or that the user asked us to be strict about:
+    //      - duplicate field names
+    //      - field names colliding with the generated items
+    //      - fields without any bit
+    //      - bits not covered by any field, when the 'strict' parameter is set
+    pub fn check_layout(&self, params: &MacroParams) -> syn::Result<()>
+    {
+        let names: Vec<&str> = self.accessors().map(|f| f.name.as_str()).collect();
+        // The clear_<field>() helpers are only generated for the w1c fields
+        let w1c_names: Vec<&str> = self.accessors().filter(|f| f.policy == Policy::WriteOneToClear).map(|f| f.name.as_str()).collect();
+        let reserved = reserved_names(params);
+
+        for (i, f) in self.accessors().enumerate()
+        {
+            if f.bsize == 0
+            {
+                return Err( syn::Error::new(f.span, format!("Field `{}` has no bits. Give it a width of at least 1 bit", f.name)) );
+            }
+
+            // Conditional fields may share a name, as long as they are not enabled together
+            let duplicate = self.accessors()
+                                .take(i)
+                                .any(|o| o.name == f.name && !(o.is_conditional() && f.is_conditional()));
+
+            if duplicate
+            {
+                return Err( syn::Error::new(f.span, format!("Duplicate field name `{}`", f.name)) );
+            }
+
+            // Variants of the field enum, rx_done and rxDone both giving RxDone
+            let variant = f.variant_name();
+            if let Some(other) = self.accessors()
+                                     .take(i)
+                                     .filter(|_| params.dynamic)
+                                     .find(|o| o.name != f.name && o.variant_name() == variant && !(o.is_conditional() && f.is_conditional()))
+            {
+                return Err( syn::Error::new(f.span, format!("Fields `{}` and `{}` both give the variant `{}` of the field enum", other.name, f.name, variant)) );
+            }
+
+            if reserved.contains(&f.name.as_str())
+            {
+                return Err( syn::Error::new(f.span, format!("Field name `{}` collides with an item generated by the bitf macro", f.name)) );
+            }
+
+            if params.view && f.name == "new"
+            {
+                return Err( syn::Error::new(f.span, "Field name `new` collides with the constructor of the views generated by the bitf macro") );
+            }
+
+            if let Some(other) = f.name.strip_prefix("set_").filter(|n| names.contains(n))
+            {
+                return Err( syn::Error::new(f.span, format!("Field name `{}` collides with the setter generated for field `{}`", f.name, other)) );
+            }
+
+            if let Some(other) = f.name.strip_prefix("clear_").filter(|n| w1c_names.contains(n))
+            {
+                return Err( syn::Error::new(f.span, format!("Field name `{}` collides with the helper generated for field `{}`", f.name, other)) );
+            }
+
+            if let Some(other) = f.name.strip_prefix("fetch_set_").filter(|n| params.atomic && names.contains(n))
+            {
+                return Err( syn::Error::new(f.span, format!("Field name `{}` collides with the atomic setter generated for field `{}`", f.name, other)) );
+            }
+        }
+
+        // Every variant selects its fields with the same value of a single tag field
+        let variants: Vec<&BitField> = self.accessors().filter(|f| f.variant.is_some()).collect();
+        if let Some(first) = variants.first().and_then(|f| f.variant.as_ref())
+        {
+            let tag = self.accessors()
+                          .find(|f| first.tag == f.name && f.variant.is_none())
+                          .ok_or_else(|| syn::Error::new(first.tag.span(), format!("The tag `{}` of the variants is not a field of the structure", first.tag)))?;
+
+            if tag.bsize > 128 || tag.is_shadow() || !tag.policy.readable()
+            {
+                return Err( syn::Error::new(first.tag.span(), "The tag of the variants must be a readable field of at most 128 bits") );
+            }
+
+            for (i, f) in variants.iter().enumerate()
+            {
+                let v = f.variant.as_ref().unwrap();
+                if v.tag != first.tag
+                {
+                    return Err( syn::Error::new(v.tag.span(), format!("Every variant must be selected by the same tag `{}`", first.tag)) );
+                }
+
+                if variants.iter().filter_map(|o| o.variant.as_ref()).any(|o| o.name == v.name && !o.same_as(v))
+                {
+                    return Err( syn::Error::new(v.name.span(), format!("Variant `{}` is selected by different values of the tag", v.name)) );
+                }
+
+                if tag.bsize < 128 && v.tag_value() >> tag.bsize != 0
+                {
+                    return Err( syn::Error::new(v.value.span(), format!("The value {} of the tag does not fit in the {} bits of `{}`", v.tag_value(), tag.bsize, tag.name)) );
+                }
+
+                if let Some(other) = variants.iter().take(i).filter_map(|o| o.variant.as_ref()).find(|o| o.name != v.name && o.tag_value() == v.tag_value())
+                {
+                    return Err( syn::Error::new(v.value.span(), format!("Variants `{}` and `{}` are selected by the same value {} of the tag", other.name, v.name, v.tag_value())) );
+                }
+            }
+        }
+
+        if params.strict
+        {
+            if let Some((lo, hi)) = self.unused_bits(&params.endianness, params.bitfield_size)
+            {
+                return Err( syn::Error::new(self.span,
+                                            format!("Bits {}..={} of the bitfield are not covered by any field. Declare them as `_reserved_{}` or remove the 'strict' parameter",
+                                                    lo, hi, hi - lo + 1)) );
+            }
+        }
+
+        Ok(())
+    }
+
+    // Const assertions replacing the size checks of conditional layouts
+    pub fn layout_assertions(&self, params: &MacroParams) -> TokenStream
+    {
+        if !self.is_conditional()
+        {
+            return quote!{};
+        }
+
+        let declared = self.declared_size_tokens();
+        let size = params.bitfield_size;
+        let strict = if params.strict
+        {
+            quote!{ assert!(#declared == #size, "Some bits of the bitfield are not covered by any field"); }
+        }
+        else
+        {
+            quote!{}
+        };
+
+        quote!
+        {
+            const _: () =
+            {
+                assert!(#declared <= #size, "Selected size for bitfield is not large enough to hold every field");
+                #strict
+            };
+        }
+    }
+
+    // Human readable table of the computed layout, most significant field first
+    pub fn layout_table(&self, params: &MacroParams) -> Vec<String>
+    {
+        let mut rows: Vec<(usize, String, usize, String)> = self.bfields.iter()
+                                                                        .map(|f|
+                                                                             {
+                                                                                let mut name = match (&f.variant, f.group, f.skip)
+                                                                                {
+                                                                                    _ if f.overlay => format!("{} [overlay]", f.name),
+                                                                                    (Some(v), _, _) => format!("{} [{}]", f.name, v.name),
+                                                                                    (None, true, _) => String::from("<variants>"),
+                                                                                    (None, false, true) => String::from("<reserved>"),
+                                                                                    (None, false, false) => f.name.clone(),
+                                                                                };
+                                                                                if let Some(pred) = f.cfg_predicate()
+                                                                                {
+                                                                                    name.push_str(&format!(" #[cfg({})]", pred));
+                                                                                }
+                                                                                (f.pos, f.range(), f.bsize, name)
+                                                                             })
+                                                                        .collect();
+
+        if let Some((lo, hi)) = self.unused_bits(&params.endianness, params.bitfield_size)
+        {
+            rows.push((lo, format!("{}..={}", lo, hi), hi - lo + 1, String::from("<unused>")));
+        }
+
+        rows.sort_by_key(|r| std::cmp::Reverse(r.0));
+
+        let mut table = vec![format!("{:<10} | {:<5} | field", "bits", "width")];
+
+        for (_, range, size, name) in rows
+        {
+            table.push(format!("{:<10} | {:<5} | {}", range, size, name));
+        }
+
+        table
+    }
+
+    // Computed layout exposed by the 'report' parameter
+    pub fn report(&self, params: &MacroParams) -> String
+    {
+        let mut out = format!("bitf layout of `{}` ({}, {})\n", self.name, params.ty, format!("{:?}", params.endianness).to_lowercase());
+
+        for line in self.layout_table(params)
+        {
+            out.push_str(&format!("  {}\n", line));
+        }
+
+        out
+    }
+
+    // Associated constant holding the computed layout, when the 'report' parameter is set
+    pub fn report_tokens(&self, params: &MacroParams) -> TokenStream
+    {
+        if !params.report
+        {
+            return quote!{};
+        }
+
+        let report = self.report(params);
+
+        quote!
+        {
+            /// Computed layout of the bitfield, to be checked against a datasheet
+            pub const LAYOUT_REPORT: &'static str = #report;
+        }
+    }
+
+    // Warnings for the computed layout, when the 'report' parameter is set, and for the bits not covered by any field,
+    // which check_layout() turns into an error in strict mode
+    // There is no stable warning API for procedural macros, so each warning is raised by the use of a deprecated item
+    // Conditional layouts are only known once the #[cfg(...)] are resolved, and their unused bits are not checked
+    pub fn layout_warnings(&self, params: &MacroParams, attrs: &mut Vec<Attribute>) -> TokenStream
+    {
+        let mut uses = Vec::new();
+
+        if params.report
+        {
+            let note = self.report(params).trim_end().to_string();
+            uses.push(quote_spanned!
+            {
+                self.span =>
+                #[deprecated(note = #note)]
+                #[allow(non_camel_case_types)]
+                struct layout_report;
+                let _ = layout_report;
+            });
+        }
+
+        if let Some((lo, hi)) = self.unused_bits(&params.endianness, params.bitfield_size).filter(|_| !params.strict)
+        {
+            let note = format!("bits {}..={} of `{}` are not covered by any field. Declare them as `_reserved_{}`, or use the 'strict' parameter to make this an error",
+                               lo, hi, self.name, hi - lo + 1);
+            uses.push(quote_spanned!
+            {
+                self.span =>
+                #[deprecated(note = #note)]
+                #[allow(non_camel_case_types)]
+                struct unused_bits;
+                let _ = unused_bits;
+            });
+        }
+
+        if uses.is_empty()
+        {
+            return quote!{};
+        }
+
+        let lints = take_lint_attrs(attrs);
+
+        quote_spanned!
+        {
+            self.span =>
+            #(#lints)*
+            const _: () =
+            {
+                #(#uses)*
+            };
+        }
+    }
+
+    // Rustdoc lines appended to the documentation of the structure
+    pub fn layout_doc(&self, params: &MacroParams) -> Vec<String>
+    {
+        let mut doc = vec![String::new(), format!("Bitfield layout ({}, {}):", params.ty, format!("{:?}", params.endianness).to_lowercase()), String::new(), String::from("```text")];
+        doc.extend(self.layout_table(params));
+        doc.push(String::from("```"));
+
+        doc
     }
 }
 
@@ -43,58 +542,502 @@ impl Parse for Strukt
         
         let vis = input.parse::<Visibility>()?;
         let strukt = input.parse::<ItemStruct>()?;
-        let mut fields = Vec::new();
+        let span = strukt.ident.span();
+        let mut fields: Vec<BitField> = Vec::new();
 
         let mut pos: usize = 0;
-        let mut map: Vec<(usize, usize)> = Vec::new();
+        // Placeholder of the group of variants being declared, and the bits declared so far by each variant
+        let mut group: Option<(usize, Vec<(Ident, usize)>)> = None;
+        // Bits of the last laid out field already shared by the overlays following it
+        let mut overlaid: usize = 0;
 
         for field in strukt.fields
         {
             let mut bfield = BitField::try_from(&field)?;
-            
-            bfield.update_pos(pos);
-            pos += bfield.bsize;
 
-            if bfield.skip
+            // Overlays share the bits of the preceding field, from its first bit
+            if bfield.overlay
             {
-                map.push((bfield.bsize, 0));
+                let host = match fields.iter().rposition(|f| !f.is_shadow())
+                {
+                    Some(host) if group.is_none() && !fields[host].group => host,
+                    _ => return Err( syn::Error::new(bfield.span, "An overlay must follow the field whose bits it shares, which cannot be part of a variant") ),
+                };
+
+                if overlaid + bfield.bsize > fields[host].bsize
+                {
+                    return Err( syn::Error::new(bfield.span, format!("Overlay `{}` exceeds the {} bits of the field it shares", bfield.name, fields[host].bsize)) );
+                }
+
+                bfield.host = Some(host);
+                bfield.offset = overlaid;
+                bfield.cfg = fields[host].cfg.clone();
+                bfield.update_pos(fields[host].pos + overlaid);
+                overlaid += bfield.bsize;
+
+                fields.push(bfield);
                 continue;
             }
-           
-            map.push((bfield.bsize, 1));
+            overlaid = 0;
+
+            let variant = match &bfield.variant
+            {
+                Some(variant) => variant.name.clone(),
+                None =>
+                {
+                    // The bits of a group of variants are taken once the group is declared
+                    if let Some((host, _)) = group.take()
+                    {
+                        pos += fields[host].bsize;
+                    }
+
+                    bfield.update_pos(pos);
+                    pos += bfield.bsize;
+
+                    fields.push(bfield);
+                    continue;
+                },
+            };
+
+            let (host, used) = group.get_or_insert_with(||
+            {
+                let mut placeholder = bfield.clone();
+                placeholder.name = String::from("_variants");
+                placeholder.bsize = 0;
+                placeholder.skip = true;
+                placeholder.group = true;
+                placeholder.variant = None;
+                placeholder.update_pos(pos);
+                fields.push(placeholder);
+
+                (fields.len() - 1, Vec::new())
+            });
+
+            // Each variant lays its fields out from the first bit of the group
+            let offset = match used.iter_mut().find(|(v, _)| *v == variant)
+            {
+                Some((_, bits)) => bits,
+                None => { used.push((variant, 0)); &mut used.last_mut().unwrap().1 },
+            };
+
+            bfield.host = Some(*host);
+            bfield.offset = *offset;
+            bfield.update_pos(pos + *offset);
+            *offset += bfield.bsize;
+            fields[*host].bsize = fields[*host].bsize.max(*offset);
+
             fields.push(bfield);
         }
 
-        let map = map.into_iter().rev().collect();
-
         Ok( Self
             {
                 name: strukt.ident,
+                span,
                 bfields: fields,
                 attrs,
                 vis,
-                map,
+                generics: strukt.generics,
             })
     }
 }
 
+// Access policy of a field, declared with #[access(..)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum Policy
+{
+    ReadWrite,          // rw, the default
+    ReadOnly,           // ro, no setter
+    WriteOnly,          // wo, no getter
+    WriteOneToClear,    // w1c, bits cleared by writing 1, through clear_<field>()
+    WriteOneToSet,      // w1s, bits set by writing 1
+}
+
+impl Policy
+{
+    pub fn readable(self) -> bool
+    {
+        self != Policy::WriteOnly
+    }
+
+    // True if the field gets a set_<field>() setter
+    pub fn writable(self) -> bool
+    {
+        matches!(self, Policy::ReadWrite | Policy::WriteOnly | Policy::WriteOneToSet)
+    }
+
+    // True if the value read must not be written back during a read-modify-write,
+    // as it would clear or set bits, or write garbage
+    pub fn masked_on_write_back(self) -> bool
+    {
+        matches!(self, Policy::WriteOnly | Policy::WriteOneToClear | Policy::WriteOneToSet)
+    }
+}
+
+impl TryFrom<&Attribute> for Policy
+{
+    type Error = syn::Error;
+
+    fn try_from(attr: &Attribute) -> Result<Self, Self::Error>
+    {
+        let policy = attr.parse_args::<Ident>()?;
+
+        match policy.to_string().as_ref()
+        {
+            "rw"    => Ok(Policy::ReadWrite),
+            "ro"    => Ok(Policy::ReadOnly),
+            "wo"    => Ok(Policy::WriteOnly),
+            "w1c"   => Ok(Policy::WriteOneToClear),
+            "w1s"   => Ok(Policy::WriteOneToSet),
+            _ => Err( syn::Error::new(policy.span(), "Wrong access policy. Policies can be: 'rw' / 'ro' / 'wo' / 'w1c' / 'w1s'") ),
+        }
+    }
+}
+
+// Variant of a tagged layout, declared with #[variant(Name, tag = value)]
+// The fields of the variants declared one after the other share the same bits
+#[derive(Clone)]
+pub struct Variant
+{
+    pub name:   Ident,                      // The name of the variant, in the enum returned by variant()
+    pub tag:    Ident,                      // The field selecting the variant
+    pub value:  LitInt,                     // The value of the tag selecting the variant
+}
+
+impl Variant
+{
+    // Value of the tag selecting the variant, checked when parsing the attribute
+    pub fn tag_value(&self) -> u128
+    {
+        self.value.base10_parse::<u128>().unwrap()
+    }
+
+    // Literal of the value of the tag, without its suffix so that it takes the type of the tag
+    pub fn tag_tokens(&self) -> TokenStream
+    {
+        Literal::u128_unsuffixed(self.tag_value()).to_token_stream()
+    }
+
+    // True if both declarations select the same variant the same way
+    // The values of the tag are compared as numbers, whatever their base or suffix
+    fn same_as(&self, other: &Variant) -> bool
+    {
+        self.name == other.name && self.tag == other.tag && self.tag_value() == other.tag_value()
+    }
+}
+
+impl TryFrom<&Attribute> for Variant
+{
+    type Error = syn::Error;
+
+    fn try_from(attr: &Attribute) -> Result<Self, Self::Error>
+    {
+        attr.parse_args_with(|input: ParseStream|
+        {
+            let name = input.parse::<Ident>()?;
+            input.parse::<Token![,]>()?;
+            let tag = input.parse::<Ident>()?;
+            input.parse::<Token![=]>()?;
+            let value = input.parse::<LitInt>()?;
+            value.base10_parse::<u128>()?;
+
+            Ok( Variant { name, tag, value } )
+        })
+        .map_err(|e| syn::Error::new(e.span(), "Expected the name of the variant and the value of its tag, as an integer literal: #[variant(Uart, mode = 0)]"))
+    }
+}
+
 #[derive(Clone)]
 pub struct BitField
 {
-    pub name:   String,
-    pub bsize:  usize,
-    pub pos:    usize,
-    pub skip:   bool,
-    pub ty:     Type,
-    pub vis: Visibility,
+    pub name:     String,
+    pub bsize:    usize,
+    pub pos:      usize,
+    pub skip:     bool,
+    pub ty:       Type,
+    pub vis:      Visibility,
+    pub span:     Span,
+    pub docs:     Vec<Attribute>,           // The doc comments of the field, copied onto its accessors
+    pub cfg:      Vec<Attribute>,           // The #[cfg(...)] attributes of the field
+    pub pos_expr: Option<TokenStream>,      // Const expression of the position, when it depends on conditional fields
+    pub policy:   Policy,                   // The access policy of the field, declared with #[access(..)]
+    pub variant:  Option<Variant>,          // The variant the field belongs to, declared with #[variant(..)]
+    pub host:     Option<usize>,            // The index of the field whose bits this field shares, if any
+    pub offset:   usize,                    // The offset of the field in the bits of its host
+    pub group:    bool,                     // True for the placeholder holding the bits of a group of variants
+    pub overlay:  bool,                     // True if the field shares the bits of the preceding field, declared with #[overlay]
 }
 
 impl BitField
 {
-    fn update_pos(self: &mut Self, position: usize)
+    fn update_pos(&mut self, position: usize)
     {
         self.pos = position;
     }
+
+    // True if the field shares the bits of another field, and takes no room in the layout
+    pub fn is_shadow(&self) -> bool
+    {
+        self.host.is_some()
+    }
+
+    // Name of the variant of the field enum, in UpperCamelCase
+    pub fn variant_name(&self) -> Ident
+    {
+        let camel: String = self.name.split('_')
+                                     .filter(|s| !s.is_empty())
+                                     .map(|s| { let mut c = s.chars(); c.next().unwrap().to_uppercase().chain(c).collect::<String>() })
+                                     .collect();
+
+        Ident::new(&camel, self.span)
+    }
+
+    // Mask of the field in the raw value, for use in quote! code generation
+    // Only meaningful when raw is not backed by an array
+    pub fn mask_tokens(&self, params: &MacroParams) -> TokenStream
+    {
+        let raw_type = &params.ty;
+        let bitfield_size = params.bitfield_size;
+        let fsize = self.bsize;
+        let fpos = self.pos_tokens();
+
+        quote!{ (#raw_type::MAX >> (#bitfield_size - #fsize) << #fpos) }
+    }
+
+    // Type of the value of the field, as stored in raw
+    //      the type of raw itself, or when backed by an array, the smallest primitive able
+    //      to hold the field, or an array of bytes for fields wider than 128 bits
+    pub fn value_type(&self, params: &MacroParams) -> TokenStream
+    {
+        if !params.is_array()
+        {
+            let raw_type = &params.ty;
+            return quote!{ #raw_type };
+        }
+
+        match self.bsize
+        {
+            0..=8       => quote!{ u8 },
+            9..=16      => quote!{ u16 },
+            17..=32     => quote!{ u32 },
+            33..=64     => quote!{ u64 },
+            65..=128    => quote!{ u128 },
+            _           => { let len = self.bsize.div_ceil(8); quote!{ [u8; #len] } },
+        }
+    }
+
+    // Type returned by the getter of the field, as computed by the Hell Match of generate_impl_code()
+    pub fn getter_type(&self, params: &MacroParams) -> TokenStream
+    {
+        match &self.ty
+        {
+            Type::Path(x) => { let ident = &x.path.segments[0].ident; quote!{ #ident } },
+            _ => self.value_type(params),
+        }
+    }
+
+    // Expression reading the value of the field from the raw field of `recv`
+    pub fn value_tokens(&self, params: &MacroParams, recv: &TokenStream) -> TokenStream
+    {
+        if params.is_array()
+        {
+            return self.bits_value_tokens(params, recv);
+        }
+
+        self.extract_tokens(params, &params.load_tokens(recv))
+    }
+
+    // Expression extracting the value of the field from the value `raw`
+    // Only meaningful when raw is not backed by an array
+    pub fn extract_tokens(&self, params: &MacroParams, raw: &TokenStream) -> TokenStream
+    {
+        let fpos = self.pos_tokens();
+        let mask = self.mask_tokens(params);
+
+        quote!{ ((#raw & #mask) >> #fpos) }
+    }
+
+    // Statements writing `val`, of the type given by value_type(), in the raw field of `recv`
+    pub fn store_tokens(&self, params: &MacroParams, recv: &TokenStream, val: &TokenStream) -> TokenStream
+    {
+        if params.is_array()
+        {
+            return self.bits_store_tokens(recv, val);
+        }
+
+        if params.atomic
+        {
+            let fetch = self.fetch_store_tokens(params, recv, val);
+            return quote!{ #fetch; };
+        }
+
+        let fpos = self.pos_tokens();
+        let mask = self.mask_tokens(params);
+
+        if params.cell
+        {
+            return quote!
+            {
+                let mask = #mask;
+                let tmp = !mask & #recv.raw.get();
+                #recv.raw.set(tmp | (#val << #fpos));
+            };
+        }
+
+        quote!
+        {
+            let mask = #mask;
+            let tmp = !mask & #recv.raw;
+            #recv.raw = tmp | (#val << #fpos);
+        }
+    }
+
+    // Expression atomically writing `val` in the raw field of `recv`, evaluating to the previous value of raw
+    // Only meaningful when raw is atomic
+    pub fn fetch_store_tokens(&self, params: &MacroParams, recv: &TokenStream, val: &TokenStream) -> TokenStream
+    {
+        let fpos = self.pos_tokens();
+        let mask = self.mask_tokens(params);
+        let load = params.ordering.load_tokens();
+        let rmw = params.ordering.rmw_tokens();
+
+        // The closure never fails, so the update always succeeds
+        quote!
+        {
+            {
+                let mask = #mask;
+                #recv.raw.fetch_update(#rmw, #load, |raw| Some((!mask & raw) | ((#val << #fpos) & mask))).unwrap()
+            }
+        }
+    }
+
+    // Expression reading the value of the field through the read_bits / read_bytes helpers of `recv`
+    // Used by bitfields backed by an array, and by the views over byte slices
+    pub fn bits_value_tokens(&self, params: &MacroParams, recv: &TokenStream) -> TokenStream
+    {
+        let fsize = self.bsize;
+        let fpos = self.pos_tokens();
+        let ty = self.value_type(params);
+
+        if fsize > 128
+        {
+            let len = fsize.div_ceil(8);
+            quote!{ #recv.read_bytes::<#len>(#fpos, #fsize) }
+        }
+        else
+        {
+            quote!{ (#recv.read_bits(#fpos, #fsize) as #ty) }
+        }
+    }
+
+    // Statements writing `val` through the write_bits / write_bytes helpers of `recv`
+    pub fn bits_store_tokens(&self, recv: &TokenStream, val: &TokenStream) -> TokenStream
+    {
+        let fsize = self.bsize;
+        let fpos = self.pos_tokens();
+
+        if fsize > 128
+        {
+            quote!{ #recv.write_bytes(#fpos, #fsize, &#val); }
+        }
+        else
+        {
+            quote!{ #recv.write_bits(#fpos, #fsize, #val as u128); }
+        }
+    }
+
+    // Zero value of the field, of the type given by value_type()
+    pub fn zero_tokens(&self, params: &MacroParams) -> TokenStream
+    {
+        if params.is_array() && self.bsize > 128
+        {
+            let len = self.bsize.div_ceil(8);
+            return quote!{ [0u8; #len] };
+        }
+
+        quote!{ 0 }
+    }
+
+    // Value of the field with all its bits set, of the type given by value_type()
+    pub fn ones_tokens(&self, params: &MacroParams) -> TokenStream
+    {
+        let fsize = self.bsize;
+        let ty = self.value_type(params);
+
+        if params.is_array() && fsize > 128
+        {
+            let len = fsize.div_ceil(8);
+            return quote!{ [0xffu8; #len] };
+        }
+
+        quote!{ ((u128::MAX >> (128 - #fsize)) as #ty) }
+    }
+
+    // Position of the field, for use in quote! code generation
+    pub fn pos_tokens(&self) -> TokenStream
+    {
+        match &self.pos_expr
+        {
+            Some(expr) => expr.clone(),
+            None => { let pos = self.pos; quote!{ #pos } },
+        }
+    }
+
+    pub fn is_conditional(&self) -> bool
+    {
+        !self.cfg.is_empty()
+    }
+
+    // Combined predicate of the #[cfg(...)] attributes of the field
+    pub fn cfg_predicate(&self) -> Option<TokenStream>
+    {
+        if self.cfg.is_empty()
+        {
+            return None;
+        }
+
+        let preds: Vec<TokenStream> = self.cfg.iter()
+                                              .filter_map(|a| a.meta.require_list().ok())
+                                              .map(|l| l.tokens.clone())
+                                              .collect();
+
+        Some( quote!{ all(#(#preds),*) } )
+    }
+
+    // Const expression of the size of the field, zero when its #[cfg(...)] does not hold
+    pub fn size_tokens(&self) -> TokenStream
+    {
+        let size = self.bsize;
+
+        match self.cfg_predicate()
+        {
+            Some(pred) => quote!{ (if cfg!(#pred) { #size } else { 0 }) },
+            None => quote!{ #size },
+        }
+    }
+
+    // Range of bits covered by the field, if it does not depend on conditional fields
+    pub fn range(&self) -> String
+    {
+        match self.pos_expr
+        {
+            Some(_) => String::from("cfg"),
+            // Fields of zero bits cover an empty range
+            None if self.bsize == 0 => format!("{}..{}", self.pos, self.pos),
+            None => format!("{}..={}", self.pos, self.pos + self.bsize - 1),
+        }
+    }
+
+    // Auto-generated documentation line for the accessors of the field
+    pub fn range_doc(&self) -> String
+    {
+        let plural = if self.bsize != 1 { "s" } else { "" };
+
+        match self.pos_expr
+        {
+            Some(_) => format!("Bits depending on the enabled configuration ({} bit{})", self.bsize, plural),
+            None => format!("Bits {} ({} bit{})", self.range(), self.bsize, plural),
+        }
+    }
 }
 
 impl TryFrom<&Field> for BitField
@@ -113,47 +1056,196 @@ impl TryFrom<&Field> for BitField
         let ident_str = ident.to_string();
         let split = rsplit(&ident_str)?;
 
-        let name: String;
-        let bsize: usize;
-
         // If the field has been effectively split in 2 parts
         // Then we can take the first part as the name
         // And try to convert the second part as a number
-        if split.len() == 2
-        {
-            name = String::from(split[0]);
-            bsize = split[1].parse::<usize>()
-                                      .or_else(|x| 
-                                               { 
-                                                 Err( syn::Error::new_spanned(field.to_token_stream(),
-                                                                              format!("{}: {}. {}", x, ident_str, ERR_FORMAT)) )
-                                               })?;
-        }
-        else
+        if split.len() != 2
         {
             // If we don't have 2 parts it probably means that the format is wrong
             return Err( syn::Error::new_spanned(field.to_token_stream(), format!("Wrong field name format. {}.", ERR_FORMAT)) );
         }
 
+        let name = String::from(split[0]);
+        let bsize = split[1].parse::<usize>()
+                                  .map_err(|x| 
+                                           { 
+                                             syn::Error::new_spanned(field.to_token_stream(),
+                                                                     format!("{}: {}. {}", x, ident_str, ERR_FORMAT))
+                                           })?;
+
         // If the name is "_reserved, we set the skip value of the BitField struct as false
         // This field will not be implemented
         let skip: bool = &name == "_reserved";
 
-        Ok(BitField { name, bsize, pos: 0, skip, ty: field.ty.clone(), vis: field.vis.clone() })
+        let docs = field.attrs.iter()
+                              .filter(|a| a.path().is_ident("doc"))
+                              .cloned()
+                              .collect();
+
+        let cfg: Vec<Attribute> = field.attrs.iter()
+                                             .filter(|a| a.path().is_ident("cfg"))
+                                             .cloned()
+                                             .collect();
+
+        let policy = match field.attrs.iter().find(|a| a.path().is_ident("access"))
+        {
+            Some(attr) => Policy::try_from(attr)?,
+            None => Policy::ReadWrite,
+        };
+
+        let variant = match field.attrs.iter().find(|a| a.path().is_ident("variant"))
+        {
+            Some(attr) => Some(Variant::try_from(attr)?),
+            None => None,
+        };
+
+        if variant.is_some() && (skip || !cfg.is_empty())
+        {
+            return Err( syn::Error::new(ident.span(), "Fields of a variant can neither be reserved nor conditional") );
+        }
+
+        let overlay = match field.attrs.iter().find(|a| a.path().is_ident("overlay"))
+        {
+            Some(attr) => { attr.meta.require_path_only()?; true },
+            None => false,
+        };
+
+        // Overlays take the #[cfg(...)] of the field they share
+        if overlay && (skip || variant.is_some() || !cfg.is_empty())
+        {
+            return Err( syn::Error::new(ident.span(), "Overlays can neither be reserved, part of a variant nor conditional") );
+        }
+
+        Ok(BitField { name, bsize, pos: 0, skip, ty: field.ty.clone(), vis: field.vis.clone(), span: ident.span(), docs, cfg, pos_expr: None, policy,
+                      variant, host: None, offset: 0, group: false, overlay })
+    }
+}
+
+// Lint attributes of the structure which apply to the item raising the layout warnings
+// The allow attributes are copied, whereas the deprecated lint is moved out of the expect attributes,
+// since the structure itself does not raise the warnings and its expectation would be unfulfilled
+fn take_lint_attrs(attrs: &mut Vec<Attribute>) -> Vec<Attribute>
+{
+    let mut lints: Vec<Attribute> = attrs.iter().filter(|a| a.path().is_ident("allow")).cloned().collect();
+
+    attrs.retain_mut(|attr|
+    {
+        if !attr.path().is_ident("expect")
+        {
+            return true;
+        }
+
+        let metas = match attr.parse_args_with(Punctuated::<syn::Meta, Token![,]>::parse_terminated)
+        {
+            Ok(metas) => metas,
+            Err(_) => return true,
+        };
+
+        if !metas.iter().any(|m| m.path().is_ident("deprecated"))
+        {
+            return true;
+        }
+
+        // The reason is given to both attributes
+        let reason: Vec<&syn::Meta> = metas.iter().filter(|m| m.path().is_ident("reason")).collect();
+        let kept: Vec<&syn::Meta> = metas.iter().filter(|m| !m.path().is_ident("reason") && !m.path().is_ident("deprecated")).collect();
+        lints.push(syn::parse_quote!{ #[expect(deprecated #(, #reason)*)] });
+
+        if kept.is_empty()
+        {
+            return false;
+        }
+
+        *attr = syn::parse_quote!{ #[expect(#(#kept),* #(, #reason)*)] };
+        true
+    });
+
+    lints
+}
+
+// Names of the items generated on the structure with the given parameters, which a field cannot take
+fn reserved_names(params: &MacroParams) -> Vec<&'static str>
+{
+    let mut names = GENERATED_ITEMS.to_vec();
+
+    if params.report
+    {
+        names.push("LAYOUT_REPORT");
+    }
+
+    if params.pprint
+    {
+        names.extend(PPRINT_ITEMS);
+    }
+
+    if params.is_array()
+    {
+        names.extend(ARRAY_ITEMS);
+    }
+
+    if params.bus.is_some()
+    {
+        names.extend(BUS_ITEMS);
+    }
+
+    if params.update
+    {
+        names.extend(UPDATE_ITEMS);
+    }
+
+    if params.dynamic
+    {
+        names.extend(DYNAMIC_ITEMS);
+    }
+
+    if params.assert
+    {
+        names.extend(ASSERT_ITEMS);
+    }
+
+    if params.pattern
+    {
+        names.extend(PATTERN_ITEMS);
     }
+
+    if params.has_bytes()
+    {
+        names.extend(BYTES_ITEMS);
+    }
+
+    if params.byte_order.is_some()
+    {
+        names.extend(BYTE_ORDER_ITEMS);
+    }
+
+    names
 }
 
 // Could have used rsplit_once, found it later...
 // Anyway, this rsplit implement a custom error throw, so it's not all for nothing I guess...
 fn rsplit(field: &str) -> Result<Vec<&str>, syn::Error>
 {
-    let idx: usize;
-
-    match field.rfind("_")
+    let idx = match field.rfind('_')
     {
-        Some(x) => idx = x,
+        Some(x) => x,
         None => return Err( syn::Error::new_spanned(field.to_token_stream(), format!("Could not find size in field name {}. {}.", field, ERR_FORMAT)) ),
-    }
+    };
 
     Ok( vec![ &field[0..idx], &field[idx+1..field.len()] ] )
 }
+
+// True if the trait is implemented by the macro, see FIELD_AWARE_DERIVES
+// Only the traits of the standard library are, either named alone or through `core::` / `std::`
+// Derives of the same name from other crates, such as derive_more::Display, are passed through
+pub fn is_field_aware(path: &Path) -> bool
+{
+    let standard = match path.segments.first()
+    {
+        Some(first) if path.segments.len() > 1 => first.ident == "core" || first.ident == "std",
+        _ => path.leading_colon.is_none(),
+    };
+
+    standard && path.segments.last()
+                             .map(|s| FIELD_AWARE_DERIVES.contains(&s.ident.to_string().as_str()))
+                             .unwrap_or(false)
+}
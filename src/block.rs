@@ -0,0 +1,105 @@
+/*
+ *
+ * This source file holds the data structures and logic
+ * for parsing register blocks where the bitf_block attribute is applied
+ *
+ */
+
+use std::convert::TryFrom;
+use quote::format_ident;
+use proc_macro2::Span;
+use syn::{ItemStruct, Field, Ident, Type, Attribute, Visibility, LitInt};
+use syn::parse::{Parse, ParseBuffer};
+
+
+static ERR_OFFSET: &str = "Expected an offset attribute on every register: #[offset(0x04)]";
+
+pub struct Block
+{
+    pub name:       Ident,                  // The name of the block
+    pub attrs:      Vec<Attribute>,         // A vector of all the declared attributes
+    pub vis:        Visibility,             // The visibility modifier of the block
+    pub regs:       Vec<Register>,          // The registers of the block, sorted by offset
+}
+
+impl Parse for Block
+{
+    fn parse(input: &ParseBuffer) -> syn::Result<Self>
+    {
+        let attrs = input.call(Attribute::parse_outer)?;
+
+        let vis = input.parse::<Visibility>()?;
+        let strukt = input.parse::<ItemStruct>()?;
+
+        let mut regs = strukt.fields.iter()
+                                    .map(Register::try_from)
+                                    .collect::<syn::Result<Vec<Register>>>()?;
+
+        regs.sort_by_key(|r| r.offset);
+
+        // Overlaps depending on the size of the registers are checked by the generated code
+        for pair in regs.windows(2)
+        {
+            if pair[0].offset == pair[1].offset
+            {
+                return Err( syn::Error::new(pair[1].span, format!("Registers `{}` and `{}` are declared at the same offset {:#x}",
+                                                                  pair[0].name, pair[1].name, pair[1].offset)) );
+            }
+        }
+
+        Ok( Self
+            {
+                name: strukt.ident,
+                attrs,
+                vis,
+                regs,
+            })
+    }
+}
+
+pub struct Register
+{
+    pub name:   Ident,
+    pub offset: usize,
+    pub ty:     Type,                       // The #[bitf] type declared for the register
+    pub reg_ty: Type,                       // The volatile wrapper generated by #[bitf(.., mmio)] for this type
+    pub vis:    Visibility,
+    pub docs:   Vec<Attribute>,
+    pub span:   Span,
+}
+
+impl TryFrom<&Field> for Register
+{
+    type Error = syn::Error;
+
+    fn try_from(field: &Field) -> Result<Self, Self::Error>
+    {
+        let name = field.ident
+                        .clone()
+                        .ok_or_else(|| syn::Error::new_spanned(field, "Expected a structure with named fields. Unnamed field given"))?;
+
+        let offset_attr = field.attrs.iter()
+                                     .find(|a| a.path().is_ident("offset"))
+                                     .ok_or_else(|| syn::Error::new_spanned(field, ERR_OFFSET))?;
+        let offset = offset_attr.parse_args::<LitInt>()?.base10_parse::<usize>()?;
+
+        // The wrapper of a #[bitf] type `Ctrl` is named `CtrlReg`
+        let mut reg_ty = field.ty.clone();
+        match &mut reg_ty
+        {
+            Type::Path(p) =>
+            {
+                let last = p.path.segments.last_mut().unwrap();
+                last.ident = format_ident!("{}Reg", last.ident);
+            },
+            _ => return Err( syn::Error::new_spanned(&field.ty, "Expected the type of a structure declared with #[bitf(.., mmio)]") ),
+        }
+
+        let docs = field.attrs.iter()
+                              .filter(|a| a.path().is_ident("doc"))
+                              .cloned()
+                              .collect();
+
+        Ok( Register { span: name.span(), name, offset, ty: field.ty.clone(), reg_ty, vis: field.vis.clone(), docs } )
+    }
+}
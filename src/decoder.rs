@@ -0,0 +1,105 @@
+/*
+ *
+ * This source file holds the data structures and logic
+ * for parsing the decoders declared with the bitf_decoder macro
+ *
+ */
+
+use syn::{Ident, Type, Attribute, Visibility, Expr, Token};
+use syn::punctuated::Punctuated;
+use syn::parse::{Parse, ParseBuffer};
+
+
+pub struct Decoder
+{
+    pub name:       Ident,                  // The name of the generated enum
+    pub attrs:      Vec<Attribute>,         // A vector of all the declared attributes
+    pub vis:        Visibility,             // The visibility modifier of the enum
+    pub ty:         Type,                   // The type of the decoded words
+    pub layouts:    Vec<Layout>,            // The layouts sharing the word, in declaration order
+}
+
+impl Parse for Decoder
+{
+    fn parse(input: &ParseBuffer) -> syn::Result<Self>
+    {
+        let attrs = input.call(Attribute::parse_outer)?;
+        let vis = input.parse::<Visibility>()?;
+        input.parse::<Token![enum]>()?;
+        let name = input.parse::<Ident>()?;
+        input.parse::<Token![:]>()?;
+        let ty = input.parse::<Type>()?;
+
+        let content;
+        syn::braced!(content in input);
+        let layouts = Punctuated::<Layout, Token![,]>::parse_terminated(&content)?;
+
+        if layouts.is_empty()
+        {
+            return Err( syn::Error::new(name.span(), "Expected at least one layout: Variant(Type) = { field: value, .. }") );
+        }
+
+        Ok( Self
+            {
+                name,
+                attrs,
+                vis,
+                ty,
+                layouts: layouts.into_iter().collect(),
+            })
+    }
+}
+
+// A variant of the decoder, such as `R(RType) = { opcode: 0b0110011 }`
+pub struct Layout
+{
+    pub variant:    Ident,
+    pub ty:         Type,                   // The #[bitf] type of the layout
+    pub docs:       Vec<Attribute>,
+    pub fields:     Vec<(Ident, Expr)>,     // The discriminating fields and their values
+}
+
+impl Parse for Layout
+{
+    fn parse(input: &ParseBuffer) -> syn::Result<Self>
+    {
+        let docs = input.call(Attribute::parse_outer)?;
+        let variant = input.parse::<Ident>()?;
+
+        let ty;
+        syn::parenthesized!(ty in input);
+        let ty = ty.parse::<Type>()?;
+
+        input.parse::<Token![=]>()?;
+
+        let content;
+        syn::braced!(content in input);
+        let fields = Punctuated::<Discriminant, Token![,]>::parse_terminated(&content)?;
+
+        Ok( Self
+            {
+                variant,
+                ty,
+                docs,
+                fields: fields.into_iter().map(|d| (d.field, d.value)).collect(),
+            })
+    }
+}
+
+struct Discriminant
+{
+    field:  Ident,
+    value:  Expr,
+}
+
+impl Parse for Discriminant
+{
+    fn parse(input: &ParseBuffer) -> syn::Result<Self>
+    {
+        let field = input.parse::<Ident>()?;
+        input.parse::<Token![:]>()?;
+        let value = input.parse::<Expr>()?;
+
+        Ok( Self { field, value } )
+    }
+}
@@ -15,14 +15,18 @@ extern crate proc_macro;
 
 mod bitfield;
 mod macroparams;
+mod block;
+mod decoder;
 
 use proc_macro::TokenStream;
 use quote::{quote, format_ident};
 use syn::{Type, Ident};
 use syn::__private::TokenStream2;
 
-use bitfield::{Strukt, BitField};
-use macroparams::{MacroParams, Endianness};
+use bitfield::{Strukt, BitField, Policy, is_field_aware};
+use block::Block;
+use decoder::Decoder;
+use macroparams::{MacroParams, Endianness, ByteOrder, SerdeRepr};
 
 
 #[proc_macro_attribute]
@@ -31,52 +35,117 @@ pub fn bitf(_meta: TokenStream, _input: TokenStream) -> TokenStream
     // Get the parameters passed in the attribute
     let params = syn::parse_macro_input!(_meta as MacroParams);
     // Extract type to be returned by the redefined structure, for use in quote! code generation
-    let raw_type = &params.ty;
+    let raw_type = params.raw_tokens();
     // Extract the size of the bitfield, for use in quote! code generation
     let bfield_size = params.bitfield_size;
 
     // Parse the structure attached to the attribute
-    let strukt = syn::parse_macro_input!(_input as Strukt);
+    let mut strukt = syn::parse_macro_input!(_input as Strukt);
     // Extract name for quote! code generation
     let name = strukt.name.clone();
-    // Extract fields for quote! code generation
-    let mut bfields = strukt.bfields.clone();
+    // Extract the traits implemented on the fields rather than derived on raw
+    // Those can be declared either in the parameters, or in a #[derive(...)] following the attribute
+    let (mut derives, passthrough): (Vec<syn::Path>, Vec<syn::Path>) = params.derives.iter().cloned().partition(is_field_aware);
+    match strukt.take_derives()
+    {
+        Ok(taken) => derives.extend(taken),
+        Err(e) => return e.to_compile_error().into(),
+    }
     // Extract attributes for quote! final code generation
-    let attrs = strukt.attrs.clone();
+    let mut attrs = strukt.attrs.clone();
+    if !passthrough.is_empty()
+    {
+        attrs.push(syn::parse_quote!{ #[derive(#(#passthrough),*)] });
+    }
     // Extract the visibility modifier of the struct
     let vis = strukt.vis.clone();
-    //
-    let map = strukt.map.clone();
+    // Extract the generics of the struct, to be repeated on every impl
+    let generics = strukt.generics.clone();
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    // Value of the structure returned by Default
+    let default = strukt.construct_tokens(&params, &params.zero_tokens());
+    // Marker field declaration, if the struct has unused generic parameters
+    let marker_decl = match strukt.phantom()
+    {
+        Some((decl, _)) => quote!{ , #decl },
+        None => quote!{},
+    };
 
     // Bitfield size failsafe
-    if !strukt.is_large_enough(bfield_size)
+    // Conditional layouts are checked once the #[cfg(...)] are resolved, see layout_assertions()
+    if !strukt.is_conditional() && !strukt.is_large_enough(bfield_size)
     {
         panic!("Selected size for bitfield is not large enough to hold every field");
     }
 
-    // if Endianness enum is set on Most Significant Bit (MSB)
-    // reverse position of the fields
-    if params.endianness == Endianness::Msb
+    // Reject duplicate or colliding names, and uncovered bits in strict mode
+    if let Err(e) = strukt.check_layout(&params)
     {
-        let mut new_pos = bfield_size;
-        let mut i = 0;
+        return e.to_compile_error().into();
+    }
 
-        for f in strukt.bfields.iter()
-        {
-            new_pos -= f.bsize;
-            bfields[i].pos = new_pos;
-            i += 1;
-        }
+    strukt.apply_order(&params.endianness, bfield_size);
+
+    // Expose the computed offset table, in addition to the warning
+    let report = strukt.report_tokens(&params);
+
+    // Warn about the computed layout and the bits not covered by any field
+    let warnings = strukt.layout_warnings(&params, &mut attrs);
+
+    // Document the layout of the bitfield on the structure
+    for line in strukt.layout_doc(&params)
+    {
+        attrs.push(syn::parse_quote!{ #[doc = #line] });
     }
 
+    // Extract fields for quote! code generation
+    let bfields: Vec<BitField> = strukt.accessors().cloned().collect();
+
+    // Generate the conversions from and to bytes
+    let bytes = generate_byte_conversions(&strukt, &params);
+
+    // Generate the views over byte slices
+    let views = generate_views(&strukt, &bfields, &params);
+
+    // Generate the batched field writes
+    let update = generate_update(&strukt, &bfields, &params);
+
+    // Generate the volatile wrapper for memory-mapped registers
+    let mmio = generate_mmio(&strukt, &params);
+
+    // Generate the register accesses through a bus backend
+    let bus = generate_bus_access(&strukt, &params);
+
+    // Generate the field aware trait implementations
+    let derived = generate_derives(&strukt, &derives, &params);
+
+    // Generate the enum of the fields
+    let field_enum = generate_field_enum(&strukt, &bfields, &params);
+
+    // Generate the pattern matching
+    let patterns = generate_patterns(&strukt, &bfields, &params);
+
+    // Generate the views over the variants of tagged layouts
+    let variants = generate_variants(&strukt, &params);
+
+    // Generate the report used by assert_bitf_eq!
+    let mismatch = generate_mismatch_report(&strukt, &params);
+
+    // Generate the serde implementations
+    let serde = generate_serde(&strukt, &params);
+
+    // Size checks of conditional layouts
+    let assertions = strukt.layout_assertions(&params);
+
     // Generate code for each declared field in the bitfield 
-    let fields = generate_impl_code(&bfields, &raw_type, bfield_size);
+    let fields = generate_impl_code(&bfields, &params, Access::Raw);
+    let array_helpers = generate_array_helpers(&params);
 
     // Generat pretty print code
     let mut pprint = quote!{};
     if params.pprint
     {
-        pprint = generate_pretty_print(&name, map, bfield_size);
+        pprint = generate_pretty_print(&strukt, &params);
     }
 
     // Generate the impl code for the pretty print
@@ -89,44 +158,473 @@ pub fn bitf(_meta: TokenStream, _input: TokenStream) -> TokenStream
     TokenStream::from(
         quote! {
                 #(#attrs)* 
-                #vis struct #name
+                #vis struct #name #generics #where_clause
                 {
-                    pub raw: #raw_type,
+                    pub raw: #raw_type
+                    #marker_decl
                 }
 
-                impl Default for #name
+                impl #impl_generics Default for #name #ty_generics #where_clause
                 {
                     fn default() -> Self
                     {
-                        #name
-                        {
-                            raw: 0x0
-                        }
+                        #default
                     }
 
                 }
 
-                impl #name
+                impl #impl_generics #name #ty_generics #where_clause
                 {
                     #(#fields)*
+
+                    #array_helpers
+
+                    #report
                 }
 
+                #warnings
+
                 #pprint
+
+                #bytes
+
+                #update
+
+                #views
+
+                #mmio
+
+                #bus
+
+                #derived
+
+                #field_enum
+
+                #patterns
+
+                #variants
+
+                #mismatch
+
+                #serde
+
+                #assertions
+            }
+        )
+}
+
+#[proc_macro_attribute]
+pub fn bitf_block(_meta: TokenStream, _input: TokenStream) -> TokenStream
+{
+    if !_meta.is_empty()
+    {
+        return syn::Error::new(proc_macro2::Span::call_site(), "The bitf_block attribute does not take any parameter")
+                          .to_compile_error()
+                          .into();
+    }
+
+    // Parse the register block attached to the attribute
+    let block = syn::parse_macro_input!(_input as Block);
+    let name = &block.name;
+    let attrs = &block.attrs;
+    let vis = &block.vis;
+
+    let mut fields = Vec::new();
+    let mut accessors = Vec::new();
+    let mut assertions = Vec::new();
+
+    for (i, reg) in block.regs.iter().enumerate()
+    {
+        let rname = &reg.name;
+        let rvis = &reg.vis;
+        let reg_ty = &reg.reg_ty;
+        let offset = reg.offset;
+        let docs = &reg.docs;
+        let pad = format_ident!("_pad{}", i);
+
+        // Padding from the end of the previous register, to the offset of this one
+        let end = match i
+        {
+            0 => quote!{ 0 },
+            _ =>
+            {
+                let prev = &block.regs[i - 1];
+                let (prev_offset, prev_ty, prev_name) = (prev.offset, &prev.reg_ty, prev.name.to_string());
+                let msg = format!("Registers `{}` and `{}` overlap", prev_name, rname);
+                assertions.push(quote!{ assert!(#prev_offset + core::mem::size_of::<#prev_ty>() <= #offset, #msg); });
+                quote!{ (#prev_offset + core::mem::size_of::<#prev_ty>()) }
+            },
+        };
+
+        let msg = format!("Register `{}` at offset {:#x} is misaligned", rname, offset);
+        assertions.push(quote!{ assert!(#offset % core::mem::align_of::<#reg_ty>() == 0, #msg); });
+        assertions.push(quote!{ assert!(core::mem::offset_of!(#name, #rname) == #offset, #msg); });
+
+        fields.push(quote!
+        {
+            #pad: [u8; #offset.saturating_sub(#end)],
+            #rname: #reg_ty,
+        });
+
+        let ty = &reg.ty;
+        let doc = format!("[`{}`] register at offset {:#x}", quote!{ #ty }.to_string().replace(' ', ""), offset);
+        let sep = if docs.is_empty() { quote!{} } else { quote!{ #[doc = ""] } };
+        accessors.push(quote!
+        {
+            #(#docs)*
+            #sep
+            #[doc = #doc]
+            #[inline]
+            #rvis fn #rname(self: &Self) -> &#reg_ty
+            {
+                &self.#rname
+            }
+        });
+    }
+
+    TokenStream::from(
+        quote! {
+                #(#attrs)*
+                #[repr(C)]
+                #vis struct #name
+                {
+                    #(#fields)*
+                }
+
+                impl #name
+                {
+                    /// Register block located at `ptr`
+                    ///
+                    /// # Safety
+                    /// `ptr` must be valid for volatile reads and writes of the whole block, and properly aligned, for the lifetime `'a`
+                    pub unsafe fn from_ptr<'a>(ptr: *mut u8) -> &'a Self
+                    {
+                        unsafe { &*(ptr as *const Self) }
+                    }
+
+                    #(#accessors)*
+                }
+
+                const _: () =
+                {
+                    #(#assertions)*
+                };
+            }
+        )
+}
+
+// Declares the backend trait used by the bitfields declared with the `bus` parameter,
+// and unless called with `no_mock`, a MockBus recording every access
+#[proc_macro]
+pub fn bitf_bus(_input: TokenStream) -> TokenStream
+{
+    let input = proc_macro2::TokenStream::from(_input);
+    let mock = match input.to_string().as_ref()
+    {
+        "" => true,
+        "no_mock" => false,
+        _ => return syn::Error::new_spanned(input, "Expected no parameter, or 'no_mock'").to_compile_error().into(),
+    };
+
+    let mock_bus = if mock
+    {
+        quote!
+        {
+            /// Access performed on a [`MockBus`]
+            #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+            pub enum BusAccess
+            {
+                Read { addr: u64, bits: u32, value: u128 },
+                Write { addr: u64, bits: u32, value: u128 },
+            }
+
+            /// Bus recording every access, for testing drivers without the hardware
+            /// Reads return the scripted responses of the address first, then the last value written at the address, or 0
+            #[derive(Debug, Default)]
+            #[allow(dead_code)]
+            pub struct MockBus
+            {
+                accesses: std::vec::Vec<BusAccess>,
+                scripted: std::collections::BTreeMap<u64, std::collections::VecDeque<u128>>,
+                memory:   std::collections::BTreeMap<u64, u128>,
+            }
+
+            #[allow(dead_code)]
+            impl MockBus
+            {
+                pub fn new() -> Self
+                {
+                    Self::default()
+                }
+
+                /// Queues the value returned by the next unscripted read at `addr`
+                pub fn script_read(&mut self, addr: u64, value: u128)
+                {
+                    self.scripted.entry(addr).or_default().push_back(value);
+                }
+
+                /// Every access performed since the creation of the bus, or the last call to clear()
+                pub fn accesses(&self) -> &[BusAccess]
+                {
+                    &self.accesses
+                }
+
+                /// Address and value of every write performed
+                pub fn writes(&self) -> std::vec::Vec<(u64, u128)>
+                {
+                    self.accesses.iter()
+                                 .filter_map(|a| match a
+                                 {
+                                     BusAccess::Write { addr, value, .. } => Some((*addr, *value)),
+                                     _ => None,
+                                 })
+                                 .collect()
+                }
+
+                /// Forgets the recorded accesses
+                pub fn clear(&mut self)
+                {
+                    self.accesses.clear();
+                }
+            }
+
+            impl Bus for MockBus
+            {
+                fn read(&mut self, addr: u64, bits: u32) -> u128
+                {
+                    let value = self.scripted.get_mut(&addr)
+                                             .and_then(|q| q.pop_front())
+                                             .or_else(|| self.memory.get(&addr).copied())
+                                             .unwrap_or(0);
+
+                    self.accesses.push(BusAccess::Read { addr, bits, value });
+                    value
+                }
+
+                fn write(&mut self, addr: u64, bits: u32, value: u128)
+                {
+                    self.memory.insert(addr, value);
+                    self.accesses.push(BusAccess::Write { addr, bits, value });
+                }
+            }
+        }
+    }
+    else
+    {
+        quote!{}
+    };
+
+    TokenStream::from(
+        quote! {
+                /// Backend performing the register accesses of the bitfields declared with the `bus` parameter
+                pub trait Bus
+                {
+                    /// Reads a word of `bits` bits at `addr`
+                    fn read(&mut self, addr: u64, bits: u32) -> u128;
+
+                    /// Writes a word of `bits` bits at `addr`
+                    fn write(&mut self, addr: u64, bits: u32, value: u128);
+                }
+
+                #mock_bus
             }
         )
 }
 
-fn generate_impl_code(bitfields: &Vec<BitField>, raw_type: &Ident, bfield_size: usize) -> Vec<TokenStream2>
+// Asserts that a bitfield is equal to the expected value, field by field: assert_bitf_eq!(actual, expected)
+// The bitfield must be declared with the `assert` parameter, which generates the report of the mismatching fields
+// On failure, the message lists the mismatching fields and, with the `pp` parameter, the diagrams of both values
+#[proc_macro]
+pub fn assert_bitf_eq(_input: TokenStream) -> TokenStream
+{
+    let parser = syn::punctuated::Punctuated::<syn::Expr, syn::Token![,]>::parse_terminated;
+    let args = match syn::parse::Parser::parse(parser, _input)
+    {
+        Ok(args) => args,
+        Err(e) => return e.to_compile_error().into(),
+    };
+
+    if args.len() < 2
+    {
+        return syn::Error::new(proc_macro2::Span::call_site(), "Expected two bitfields to compare, optionally followed by a message: assert_bitf_eq!(actual, expected, \"msg\")")
+                          .to_compile_error()
+                          .into();
+    }
+
+    let actual = &args[0];
+    let expected = &args[1];
+    let msg: Vec<&syn::Expr> = args.iter().skip(2).collect();
+    let msg = if msg.is_empty() { quote!{ "" } } else { quote!{ format_args!(": {}", format_args!(#(#msg),*)) } };
+
+    TokenStream::from(
+        quote!
+        {
+            match (&(#actual), &(#expected))
+            {
+                (actual, expected) =>
+                {
+                    if let Some(report) = actual.mismatch_report(expected)
+                    {
+                        panic!("assertion `actual == expected` failed{}\n{}", #msg, report);
+                    }
+                }
+            }
+        }
+    )
+}
+
+// Pattern of a bitfield such as "0000_10xx", checked at compile time: bitf_pattern!(Type, "0000_10xx")
+// Expands to the (value, mask) pair returned by Type::from_pattern(), evaluated in a const block
+// The type must be declared with the `pattern` parameter
+#[proc_macro]
+pub fn bitf_pattern(_input: TokenStream) -> TokenStream
+{
+    let parser = |input: syn::parse::ParseStream| -> syn::Result<(syn::Type, syn::LitStr)>
+    {
+        let ty = input.parse::<syn::Type>()?;
+        input.parse::<syn::Token![,]>()?;
+        let pattern = input.parse::<syn::LitStr>()?;
+        Ok((ty, pattern))
+    };
+    let (ty, pattern) = match syn::parse::Parser::parse(parser, _input)
+    {
+        Ok(args) => args,
+        Err(e) => return e.to_compile_error().into(),
+    };
+
+    // The length of the pattern is checked against the size of the bitfield when evaluating from_pattern()
+    if let Some(c) = pattern.value().chars().find(|c| !matches!(c, '0' | '1' | 'x' | 'X' | '_' | ' '))
+    {
+        return syn::Error::new(pattern.span(), format!("Invalid character `{}` in pattern, expected '0', '1', 'x' or '_'", c))
+                          .to_compile_error()
+                          .into();
+    }
+
+    TokenStream::from(quote!{ const { <#ty>::from_pattern(#pattern) } })
+}
+
+// Enum of several layouts sharing a single word, selected by the values of some of their fields
+// The discriminating values of two layouts must not be able to match the same word
+// The layouts must be declared with the `pattern` parameter, their patterns being built with pattern()
+#[proc_macro]
+pub fn bitf_decoder(_input: TokenStream) -> TokenStream
+{
+    let decoder = syn::parse_macro_input!(_input as Decoder);
+    let name = &decoder.name;
+    let attrs = &decoder.attrs;
+    let vis = &decoder.vis;
+    let ty = &decoder.ty;
+
+    let mut variants = Vec::new();
+    let mut patterns = Vec::new();
+    let mut decodes = Vec::new();
+    let mut encodes = Vec::new();
+    let mut assertions = Vec::new();
+
+    for (i, layout) in decoder.layouts.iter().enumerate()
+    {
+        let variant = &layout.variant;
+        let lty = &layout.ty;
+        let docs = &layout.docs;
+        let pattern = format_ident!("PATTERN_{}", variant);
+        let fields = layout.fields.iter().map(|(f, v)| quote!{ .#f(#v) });
+
+        variants.push(quote!{ #(#docs)* #variant(#lty), });
+        // Patterns of the layouts, built with the pattern() of their #[bitf] type
+        patterns.push(quote!{ #[allow(non_upper_case_globals)] const #pattern: (#ty, #ty) = { let p = <#lty>::pattern()#(#fields)*; (p.value, p.mask) }; });
+        decodes.push(quote!
+        {
+            if raw & Self::#pattern.1 == Self::#pattern.0
+            {
+                type Layout = #lty;
+                return Some(#name::#variant(Layout { raw }));
+            }
+        });
+        encodes.push(quote!{ #name::#variant(v) => v.raw, });
+
+        for other in decoder.layouts.iter().take(i)
+        {
+            let other_pattern = format_ident!("PATTERN_{}", other.variant);
+            let msg = format!("The discriminating fields of `{}` and `{}` can match the same word", other.variant, variant);
+            assertions.push(quote!
+            {
+                assert!((#name::#pattern.0 ^ #name::#other_pattern.0) & #name::#pattern.1 & #name::#other_pattern.1 != 0, #msg);
+            });
+        }
+    }
+
+    TokenStream::from(
+        quote!
+        {
+            #(#attrs)*
+            #vis enum #name
+            {
+                #(#variants)*
+            }
+
+            impl #name
+            {
+                #(#patterns)*
+
+                /// Layout selected by the discriminating fields of the word, if any
+                pub fn decode(raw: #ty) -> Option<Self>
+                {
+                    #(#decodes)*
+                    None
+                }
+
+                /// Word holding the decoded layout
+                pub fn encode(self: &Self) -> #ty
+                {
+                    match self
+                    {
+                        #(#encodes)*
+                    }
+                }
+            }
+
+            const _: () =
+            {
+                #(#assertions)*
+            };
+        }
+    )
+}
+
+// How the generated accessors reach the bits of the bitfield
+#[derive(Clone, Copy, PartialEq)]
+enum Access
 {
+    Raw,            // Through the raw field of the structure
+    View,           // Through the helpers of a read-only view over a byte slice
+    ViewMut,        // Through the helpers of a mutable view over a byte slice
+}
+
+fn generate_impl_code(bitfields: &[BitField], params: &MacroParams, access: Access) -> Vec<TokenStream2>
+{
+    let recv = quote!{ self };
+    // The views are always written through a mutable reference
+    let mut_recv = if access == Access::Raw { params.setter_receiver() } else { quote!{ self: &mut Self } };
+
     bitfields.iter()
            .map(|field| 
                {
                     // Quote! variables formating for correct interpolation
                     let fname = format_ident!("{}", field.name);
                     let set_n = format_ident!("set_{}", field.name);
-                    let fsize = field.bsize;
-                    let fpos = field.pos;
                     let vis = &field.vis;
+                    let docs = &field.docs;
+                    let cfg = &field.cfg;
+                    let range_doc = field.range_doc();
+                    // Separate the user documentation from the generated one
+                    let sep = if docs.is_empty() { quote!{} } else { quote!{ #[doc = ""] } };
+                    // Type and value of the field as stored in raw
+                    let raw_ty = field.value_type(params);
+                    let (raw_value, store) = match access
+                    {
+                        Access::Raw => (field.value_tokens(params, &recv), field.store_tokens(params, &recv, &quote!{ val })),
+                        _ => (field.bits_value_tokens(params, &recv), field.bits_store_tokens(&recv, &quote!{ val })),
+                    };
                                 
 
                     // Hell Match
@@ -137,29 +635,28 @@ fn generate_impl_code(bitfields: &Vec<BitField>, raw_type: &Ident, bfield_size:
                     //  the macro uses the .into() variation. So it is up to the
                     //  user to either implement the From trait, from which the Into
                     //  trait will be deducted, or directly the Into trait
-                    let mut ty = raw_type.clone();
+                    let mut ty = raw_ty.clone();
                     let return_line = match &field.ty
                     {
                         // If we have something that ressembles a Type
                         Type::Path(x) =>
                                     {
-                                        match x.path.segments[0].ident.to_string().as_ref()
+                                        let ident = &x.path.segments[0].ident;
+                                        match ident.to_string().as_ref()
                                         {
                                             // Primitive type coercion
                                             "u8" | "u16" | "u32" | "u64" | "u128" | 
                                             "i8" | "i16" | "i32" | "i64" | "i128" => 
                                             {
-                                                //let t = &field.ty;
-                                                //quote!{#t}
-                                                ty = format_ident!("{}", x.path.segments[0].ident);
-                                                quote!{((self.raw & mask) >> #fpos) as #ty}
+                                                ty = quote!{ #ident };
+                                                quote!{ #raw_value as #ty }
                                             },
                                             // Anything else will need to implement the From trait
                                             _ => 
                                             {
-                                                ty = format_ident!("{}", x.path.segments[0].ident);
+                                                ty = quote!{ #ident };
                                                 quote!{
-                                                    let res = ((self.raw & mask) >> #fpos);
+                                                    let res = #raw_value;
                                                     res.into()
                                                     }
                                             }
@@ -168,9 +665,9 @@ fn generate_impl_code(bitfields: &Vec<BitField>, raw_type: &Ident, bfield_size:
                         // If we have a Tuple, we consider only the empty one ()
                         Type::Tuple(x) => 
                                     {
-                                        if x.elems.len() == 0
+                                        if x.elems.is_empty()
                                         {
-                                            quote!{ ((self.raw & mask) >> #fpos) as #ty }
+                                            quote!{ #raw_value }
                                         }
                                         else
                                         {
@@ -181,67 +678,356 @@ fn generate_impl_code(bitfields: &Vec<BitField>, raw_type: &Ident, bfield_size:
                         _ => panic!("Unrecognized return type."),
                     };
 
-                    // Quote! code generation
-                    // This section generates the impl code for each field on the
-                    // struct (get / set)
-                    quote!
+                    // Read-only views and fields do not implement setters
+                    let setter = if access == Access::View || !field.policy.writable()
+                    {
+                        quote!{}
+                    }
+                    else
+                    {
+                        quote!
+                        {
+                            #(#cfg)*
+                            #(#docs)*
+                            #sep
+                            #[doc = #range_doc]
+                            #[inline]
+                            #[allow(non_snake_case)]
+                            #vis fn #set_n(#mut_recv, val: #raw_ty)
+                            {
+                                #store
+                            }
+                        }
+                    };
+
+                    // Atomic bitfields also return the previous value of the field they write
+                    let fetch_setter = if !params.atomic || access != Access::Raw || !field.policy.writable()
+                    {
+                        quote!{}
+                    }
+                    else
+                    {
+                        let fetch_set_n = format_ident!("fetch_set_{}", field.name);
+                        let prev = field.fetch_store_tokens(params, &recv, &quote!{ val });
+                        let prev_value = field.extract_tokens(params, &quote!{ prev });
+
+                        quote!
+                        {
+                            #(#cfg)*
+                            #(#docs)*
+                            #sep
+                            #[doc = #range_doc]
+                            #[doc = ""]
+                            #[doc = "Atomically writes the field, and returns its previous value"]
+                            #[inline]
+                            #[allow(non_snake_case)]
+                            #vis fn #fetch_set_n(#mut_recv, val: #raw_ty) -> #raw_ty
+                            {
+                                let prev = #prev;
+                                #prev_value
+                            }
+                        }
+                    };
+
+                    // Write-1-to-clear fields are cleared by setting all their bits in the value to be written
+                    let clear = if access == Access::View || field.policy != Policy::WriteOneToClear
+                    {
+                        quote!{}
+                    }
+                    else
                     {
-                        #[inline]
-                        #[allow(non_snake_case)]
-                        #vis fn #fname(self: &Self) -> #ty
+                        let clear_n = format_ident!("clear_{}", field.name);
+                        let ones = field.ones_tokens(params);
+                        let store_ones = match access
+                        {
+                            Access::Raw => field.store_tokens(params, &recv, &ones),
+                            _ => field.bits_store_tokens(&recv, &ones),
+                        };
+
+                        quote!
                         {
-                            let mask = #raw_type::MAX >> (#bfield_size - #fsize) << #fpos;
-                            #return_line
+                            #(#cfg)*
+                            #(#docs)*
+                            #sep
+                            #[doc = #range_doc]
+                            #[doc = ""]
+                            #[doc = "Write-1-to-clear: sets every bit of the field, so that writing the value clears them"]
+                            #[inline]
+                            #[allow(non_snake_case)]
+                            #vis fn #clear_n(#mut_recv)
+                            {
+                                #store_ones
+                            }
                         }
+                    };
 
-                        #[inline]
-                        #[allow(non_snake_case)]
-                        #vis fn #set_n(self: &mut Self, val: #raw_type)
+                    // Write-only fields do not implement getters
+                    let getter = if !field.policy.readable()
+                    {
+                        quote!{}
+                    }
+                    else
+                    {
+                        quote!
                         {
-                            let mask = #raw_type::MAX >> (#bfield_size - #fsize) << #fpos;
-                            let tmp = !mask & self.raw;
-                            self.raw = tmp | (val << #fpos);
+                            #(#cfg)*
+                            #(#docs)*
+                            #sep
+                            #[doc = #range_doc]
+                            #[inline]
+                            #[allow(non_snake_case)]
+                            #vis fn #fname(self: &Self) -> #ty
+                            {
+                                #return_line
+                            }
                         }
+                    };
+
+                    // Quote! code generation
+                    // This section generates the impl code for each field on the
+                    // struct (get / set)
+                    quote!
+                    {
+                        #getter
+
+                        #setter
+
+                        #fetch_setter
+
+                        #clear
                     }
                })
            .collect()
 }
 
-fn generate_pretty_print(struct_name: &Ident, map: Vec<(usize, usize)>, field_size: usize) -> TokenStream2
+// Helpers reading and writing ranges of bits spanning several elements of raw,
+// when the bitfield is backed by an array. Element 0 holds the least significant bits
+fn generate_array_helpers(params: &MacroParams) -> TokenStream2
 {
-    //let format_string = format_ident!("{{:0{}b}}", field_size);
-    let format_string = format!("{{:0{}b}}", field_size);
+    if !params.is_array()
+    {
+        return quote!{};
+    }
 
-    let quoted_map: Vec<TokenStream2> = map.iter()
-                                           .map(|t|
-                                                {
-                                                    let size = t.0;
-                                                    let access = t.1;
+    generate_bit_helpers(&params.ty, params.elem_size(), &quote!{ self.raw }, |idx| idx, true)
+}
 
-                                                    quote!
-                                                    {
-                                                        (#size,#access)
-                                                    }
-                                                })
-                                            .collect();
+// Helpers reading and writing ranges of bits spanning several elements of `data`
+//      `index` maps the number of an element, counted from the least significant bits,
+//      to its index in `data`
+fn generate_bit_helpers(elem: &Ident, width: usize, data: &TokenStream2, index: impl Fn(TokenStream2) -> TokenStream2, mutable: bool) -> TokenStream2
+{
+    let idx = index(quote!{ (bit / #width) });
 
-    quote!
+    let writers = if mutable
     {
-        impl #struct_name
+        quote!
         {
-            pub fn pprint(self: &Self)
+            #[inline]
+            #[allow(dead_code)]
+            fn write_bits(self: &mut Self, pos: usize, size: usize, val: u128)
             {
-                let map = vec![#(#quoted_map),*];
-                //let raw_bin = format!(#format_string, &raw);
-                let raw_bin = format!(#format_string, self.raw);
+                let mut done = 0;
 
-                self.print_scale(&map, raw_bin.len());
-                self.print_line(&map, &raw_bin, ("┌", "┬", "┐"), false);
-                self.print_line(&map, &raw_bin, ("│", " │", " │"), true);
-                self.print_line(&map, &raw_bin, ("└", "┴", "┘"), false);
-            }
+                while done < size
+                {
+                    let bit = pos + done;
+                    let off = bit % #width;
+                    let take = core::cmp::min(#width - off, size - done);
+                    let mask = #elem::MAX >> (#width - take) << off;
+                    let chunk = ((val >> done) as #elem) << off;
 
-            fn print_scale(self: &Self, map: &Vec<(usize, usize)>, size: usize)
+                    #data[#idx] = (#data[#idx] & !mask) | (chunk & mask);
+                    done += take;
+                }
+            }
+
+            #[inline]
+            #[allow(dead_code)]
+            fn write_bytes<const N: usize>(self: &mut Self, pos: usize, size: usize, val: &[u8; N])
+            {
+                for (i, byte) in val.iter().enumerate()
+                {
+                    self.write_bits(pos + i * 8, core::cmp::min(8, size - i * 8), *byte as u128);
+                }
+            }
+        }
+    }
+    else
+    {
+        quote!{}
+    };
+
+    quote!
+    {
+        #[inline]
+        #[allow(dead_code)]
+        fn read_bits(self: &Self, pos: usize, size: usize) -> u128
+        {
+            let mut val: u128 = 0;
+            let mut done = 0;
+
+            while done < size
+            {
+                let bit = pos + done;
+                let off = bit % #width;
+                let take = core::cmp::min(#width - off, size - done);
+                let chunk = (#data[#idx] >> off) as u128 & (u128::MAX >> (128 - take));
+
+                val |= chunk << done;
+                done += take;
+            }
+
+            val
+        }
+
+        #[inline]
+        #[allow(dead_code)]
+        fn read_bytes<const N: usize>(self: &Self, pos: usize, size: usize) -> [u8; N]
+        {
+            let mut bytes = [0u8; N];
+
+            for (i, byte) in bytes.iter_mut().enumerate()
+            {
+                *byte = self.read_bits(pos + i * 8, core::cmp::min(8, size - i * 8)) as u8;
+            }
+
+            bytes
+        }
+
+        #writers
+    }
+}
+
+fn generate_pretty_print(strukt: &Strukt, params: &MacroParams) -> TokenStream2
+{
+    let endianness = &params.endianness;
+    let field_size = params.bitfield_size;
+    let struct_name = &strukt.name;
+    let bitfields = &strukt.bfields;
+    let (impl_generics, ty_generics, where_clause) = strukt.generics.split_for_impl();
+
+    //let format_string = format_ident!("{{:0{}b}}", field_size);
+    let format_string = format!("{{:0{}b}}", field_size);
+    // Elements of an array are printed from the last one, holding the most significant bits
+    let raw_bin = if params.is_array()
+    {
+        let elem_format = format!("{{:0{}b}}", params.elem_size());
+        quote!{ self.raw.iter().rev().map(|e| format!(#elem_format, e)).collect::<String>() }
+    }
+    else
+    {
+        let raw = params.load_tokens(&quote!{ self });
+        quote!{ format!(#format_string, #raw) }
+    };
+
+    // The map is printed from the most significant bit
+    let mut ordered: Vec<&BitField> = bitfields.iter().collect();
+    if *endianness == Endianness::Lsb
+    {
+        ordered.reverse();
+    }
+
+    // Fields sharing the bits of another field are not drawn, the bits of a group of variants are drawn as a single field
+    let quoted_map: Vec<TokenStream2> = ordered.iter()
+                                               .filter(|f| !f.is_shadow())
+                                               .map(|f|
+                                                    {
+                                                        let size = f.bsize;
+                                                        let access = if f.skip && !f.group { 0usize } else { 1usize };
+                                                        let cfg = &f.cfg;
+
+                                                        quote!
+                                                        {
+                                                            #(#cfg)*
+                                                            map.push((#size,#access));
+                                                        }
+                                                    })
+                                                .collect();
+
+    // Bits not covered by any field are displayed as reserved
+    let unused = match endianness
+    {
+        Endianness::Lsb => quote!{ map.insert(0, (#field_size - used, 0)); },
+        Endianness::Msb => quote!{ map.push((#field_size - used, 0)); },
+    };
+
+    quote!
+    {
+        impl #impl_generics #struct_name #ty_generics #where_clause
+        {
+            pub fn pprint(self: &Self)
+            {
+                print!("{}", self.pprint_render(None));
+            }
+
+            /// Prints the values of `self` and `other` one above the other, marking the fields which differ
+            pub fn pprint_diff(self: &Self, other: &Self)
+            {
+                print!("{}", self.pprint_render(Some(other)));
+            }
+
+            // Sizes of the cells of the diagram, from the most significant bit, and whether they are accessible
+            fn pprint_map(self: &Self) -> Vec<(usize, usize)>
+            {
+                let mut map: Vec<(usize, usize)> = Vec::new();
+                #(#quoted_map)*
+
+                let used: usize = map.iter().map(|m| m.0).sum();
+                if used < #field_size
+                {
+                    #unused
+                }
+
+                map
+            }
+
+            fn pprint_bits(self: &Self) -> String
+            {
+                #raw_bin
+            }
+
+            // Diagram of the value, or of the values of self and other with their differences marked below
+            fn pprint_render(self: &Self, other: Option<&Self>) -> String
+            {
+                let map = self.pprint_map();
+                let raw_bin = self.pprint_bits();
+                let mut out = String::new();
+
+                self.print_scale(&mut out, &map, raw_bin.len());
+                self.print_line(&mut out, &map, &raw_bin, ("┌", "┬", "┐"), false);
+                self.print_line(&mut out, &map, &raw_bin, ("│", " │", " │"), true);
+
+                if let Some(other) = other
+                {
+                    let other_bin = other.pprint_bits();
+                    self.print_line(&mut out, &map, &other_bin, ("├", "┼", "┤"), false);
+                    self.print_line(&mut out, &map, &other_bin, ("│", " │", " │"), true);
+                    self.print_line(&mut out, &map, &other_bin, ("└", "┴", "┘"), false);
+
+                    // Reserved cells are not compared
+                    let mut marks = String::from(" ");
+                    let mut start = 0;
+                    for (val, access) in &map
+                    {
+                        let changed = *access == 1 && raw_bin[start..start + val] != other_bin[start..start + val];
+                        marks.push(' ');
+                        marks.push_str(&(if changed { "^" } else { " " }).repeat(*val));
+                        marks.push_str("  ");
+                        start += val;
+                    }
+                    out.push_str(marks.trim_end());
+                    out.push('\n');
+                }
+                else
+                {
+                    self.print_line(&mut out, &map, &raw_bin, ("└", "┴", "┘"), false);
+                }
+
+                out
+            }
+
+            fn print_scale(self: &Self, out: &mut String, map: &[(usize, usize)], size: usize)
             {
                 let mut start = 0;
                 for (val, _) in map
@@ -249,18 +1035,18 @@ fn generate_pretty_print(struct_name: &Ident, map: Vec<(usize, usize)>, field_si
                     let w = 3 + val;
                     let scale = format!("{}", size - start);
 
-                    print!("{}", scale);
-                    print!("{}", " ".repeat(w - scale.len()));
+                    out.push_str(&scale);
+                    out.push_str(&" ".repeat(w - scale.len()));
 
                     start += val;
                 }
 
-                println!("0");
+                out.push_str("0\n");
             }
 
-            fn print_line(self: &Self, map: &Vec<(usize, usize)>, raw_bin: &String, syms: (&str, &str, &str), core: bool)
+            fn print_line(self: &Self, out: &mut String, map: &[(usize, usize)], raw_bin: &str, syms: (&str, &str, &str), core: bool)
             {
-                print!("{}", syms.0);
+                out.push_str(syms.0);
 
                 let mut iter = map.iter().peekable();
 
@@ -276,28 +1062,1828 @@ fn generate_pretty_print(struct_name: &Ident, map: Vec<(usize, usize)>, field_si
                         {
                             dsp = "r".repeat(*val);
                         }
-                        print!(" {}", dsp); 
+                        out.push(' ');
+                        out.push_str(&dsp);
                     }
                     else
                     {
                         for _ in 0..(val+2)
                         {
-                            print!("─");
+                            out.push('─');
                         }
                     }
                     
                     if iter.peek().is_none()
                     {
-                        print!("{}", syms.2);
+                        out.push_str(syms.2);
                     }
                     else
                     {
-                        print!("{}", syms.1);
+                        out.push_str(syms.1);
                     }
                     start += val;
                 }
                 
-                println!("");
+                out.push('\n');
+            }
+        }
+    }
+}
+
+fn generate_byte_conversions(strukt: &Strukt, params: &MacroParams) -> TokenStream2
+{
+    if !params.has_bytes()
+    {
+        return quote!{};
+    }
+
+    let name = &strukt.name;
+    let (impl_generics, ty_generics, where_clause) = strukt.generics.split_for_impl();
+    let elem = &params.ty;
+    let nbytes = params.bitfield_size / 8;
+
+    let (to_be, to_le, from_be, from_le) = match params.array_len
+    {
+        // Big endian order starts with the last element, holding the most significant bits
+        Some(len) =>
+        {
+            let w = params.elem_size() / 8;
+            let to = |rev: TokenStream2, conv: TokenStream2| quote!
+            {
+                let mut bytes = [0u8; #nbytes];
+                for (i, e) in self.raw.iter()#rev.enumerate()
+                {
+                    bytes[i * #w..(i + 1) * #w].copy_from_slice(&e.#conv());
+                }
+                bytes
+            };
+            let from = |idx: TokenStream2, conv: TokenStream2| quote!
+            {
+                let mut raw = [0 as #elem; #len];
+                for i in 0..#len
+                {
+                    raw[#idx] = #elem::#conv(bytes[i * #w..(i + 1) * #w].try_into().unwrap());
+                }
+                raw
+            };
+
+            (to(quote!{ .rev() }, quote!{ to_be_bytes }),
+             to(quote!{}, quote!{ to_le_bytes }),
+             from(quote!{ #len - 1 - i }, quote!{ from_be_bytes }),
+             from(quote!{ i }, quote!{ from_le_bytes }))
+        },
+        None =>
+        {
+            let raw = params.load_tokens(&quote!{ self });
+            (quote!{ #raw.to_be_bytes() },
+             quote!{ #raw.to_le_bytes() },
+             quote!{ #elem::from_be_bytes(bytes) },
+             quote!{ #elem::from_le_bytes(bytes) })
+        },
+    };
+
+    let from_be = strukt.construct_tokens(params, &quote!{ { #from_be } });
+    let from_le = strukt.construct_tokens(params, &quote!{ { #from_le } });
+
+    // Conversions using the canonical byte order, when one is declared
+    let canonical = match &params.byte_order
+    {
+        Some(order) =>
+        {
+            let (to, from) = match order
+            {
+                ByteOrder::Big => (quote!{ to_be_bytes }, quote!{ from_be_bytes }),
+                ByteOrder::Little => (quote!{ to_le_bytes }, quote!{ from_le_bytes }),
+            };
+
+            quote!
+            {
+                /// Bytes of the bitfield, in its canonical byte order
+                pub fn to_bytes(self: &Self) -> [u8; #nbytes]
+                {
+                    self.#to()
+                }
+
+                /// Bitfield read from bytes in its canonical byte order
+                pub fn from_bytes(bytes: [u8; #nbytes]) -> Self
+                {
+                    Self::#from(bytes)
+                }
+
+                /// Bitfield read from the first bytes of a buffer, in its canonical byte order
+                /// Returns None if the buffer is too short
+                pub fn from_slice(buf: &[u8]) -> Option<Self>
+                {
+                    let bytes = buf.get(..#nbytes)?;
+                    Some(Self::#from(bytes.try_into().ok()?))
+                }
+            }
+        },
+        None => quote!{},
+    };
+
+    quote!
+    {
+        impl #impl_generics #name #ty_generics #where_clause
+        {
+            /// Bytes of the bitfield, most significant byte first
+            pub fn to_be_bytes(self: &Self) -> [u8; #nbytes]
+            {
+                #to_be
+            }
+
+            /// Bytes of the bitfield, least significant byte first
+            pub fn to_le_bytes(self: &Self) -> [u8; #nbytes]
+            {
+                #to_le
+            }
+
+            /// Bitfield read from bytes, most significant byte first
+            pub fn from_be_bytes(bytes: [u8; #nbytes]) -> Self
+            {
+                #from_be
+            }
+
+            /// Bitfield read from bytes, least significant byte first
+            pub fn from_le_bytes(bytes: [u8; #nbytes]) -> Self
+            {
+                #from_le
+            }
+
+            #canonical
+        }
+    }
+}
+
+fn generate_views(strukt: &Strukt, bitfields: &[BitField], params: &MacroParams) -> TokenStream2
+{
+    let order = match (&params.byte_order, params.view)
+    {
+        (Some(order), true) => order,
+        _ => return quote!{},
+    };
+
+    let vis = &strukt.vis;
+    let name = &strukt.name;
+    let view_ref = format_ident!("{}Ref", name);
+    let view_mut = format_ident!("{}Mut", name);
+    let nbytes = params.bitfield_size / 8;
+    let u8_ident = format_ident!("u8");
+
+    // Byte holding the bits `bit..bit + 8`, in the byte order of the view
+    let index = |idx: TokenStream2| match order
+    {
+        ByteOrder::Big => quote!{ (#nbytes - 1 - #idx) },
+        ByteOrder::Little => idx,
+    };
+    let ref_helpers = generate_bit_helpers(&u8_ident, 8, &quote!{ self.buf }, index, false);
+    let mut_helpers = generate_bit_helpers(&u8_ident, 8, &quote!{ self.buf }, index, true);
+    let ref_fields = generate_impl_code(bitfields, params, Access::View);
+    let mut_fields = generate_impl_code(bitfields, params, Access::ViewMut);
+
+    let ref_doc = format!("Read-only view of a [`{}`] over a byte slice, in its canonical byte order", name);
+    let mut_doc = format!("Mutable view of a [`{}`] over a byte slice, in its canonical byte order", name);
+
+    quote!
+    {
+        #[doc = #ref_doc]
+        #vis struct #view_ref<'a>
+        {
+            buf: &'a [u8],
+        }
+
+        impl<'a> #view_ref<'a>
+        {
+            /// Size in bytes of the view
+            pub const SIZE: usize = #nbytes;
+
+            /// View over the first bytes of a buffer. Returns None if the buffer is too short
+            pub fn new(buf: &'a [u8]) -> Option<Self>
+            {
+                Some( Self { buf: buf.get(..#nbytes)? } )
+            }
+
+            #(#ref_fields)*
+
+            #ref_helpers
+        }
+
+        #[doc = #mut_doc]
+        #vis struct #view_mut<'a>
+        {
+            buf: &'a mut [u8],
+        }
+
+        impl<'a> #view_mut<'a>
+        {
+            /// Size in bytes of the view
+            pub const SIZE: usize = #nbytes;
+
+            /// View over the first bytes of a buffer. Returns None if the buffer is too short
+            pub fn new(buf: &'a mut [u8]) -> Option<Self>
+            {
+                Some( Self { buf: buf.get_mut(..#nbytes)? } )
+            }
+
+            #(#mut_fields)*
+
+            #mut_helpers
+        }
+    }
+}
+
+// Writer proxy collecting field writes, applied to raw with a single mask and merge
+// Bitfields backed by an array do not implement it
+fn generate_update(strukt: &Strukt, bitfields: &[BitField], params: &MacroParams) -> TokenStream2
+{
+    if !params.update
+    {
+        return quote!{};
+    }
+
+    let vis = &strukt.vis;
+    let name = &strukt.name;
+    let writer = format_ident!("{}Writer", name);
+    let (impl_generics, ty_generics, where_clause) = strukt.generics.split_for_impl();
+    let ty = &params.ty;
+    let writable: Vec<&BitField> = bitfields.iter().filter(|f| f.policy.writable()).collect();
+
+    let setters = writable.iter().map(|field|
+    {
+        let set_n = format_ident!("set_{}", field.name);
+        let cfg = &field.cfg;
+        let docs = &field.docs;
+        let range_doc = field.range_doc();
+        let sep = if docs.is_empty() { quote!{} } else { quote!{ #[doc = ""] } };
+        let fpos = field.pos_tokens();
+        let mask = field.mask_tokens(params);
+
+        quote!
+        {
+            #(#cfg)*
+            #(#docs)*
+            #sep
+            #[doc = #range_doc]
+            #[inline]
+            #[allow(non_snake_case)]
+            pub fn #set_n(self: &mut Self, val: #ty) -> &mut Self
+            {
+                let mask = #mask;
+                self.mask |= mask;
+                self.bits = (self.bits & !mask) | ((val << #fpos) & mask);
+                self
+            }
+        }
+    });
+
+    let clears = bitfields.iter().filter(|f| f.policy == Policy::WriteOneToClear).map(|field|
+    {
+        let clear_n = format_ident!("clear_{}", field.name);
+        let cfg = &field.cfg;
+        let doc = format!("Sets every bit of `{}`, so that writing the value clears them", field.name);
+        let mask = field.mask_tokens(params);
+
+        quote!
+        {
+            #(#cfg)*
+            #[doc = #doc]
+            #[inline]
+            #[allow(non_snake_case)]
+            pub fn #clear_n(self: &mut Self) -> &mut Self
+            {
+                self.mask |= #mask;
+                self.bits |= #mask;
+                self
+            }
+        }
+    });
+
+    // The fields of the variants and the overlays share their bits, and are written with update()
+    let owned: Vec<&&BitField> = writable.iter().filter(|f| !f.is_shadow()).collect();
+    let args = owned.iter().map(|field|
+    {
+        let fname = format_ident!("{}", field.name);
+        let cfg = &field.cfg;
+        quote!{ #(#cfg)* #fname: #ty }
+    });
+    let calls = owned.iter().map(|field|
+    {
+        let fname = format_ident!("{}", field.name);
+        let set_n = format_ident!("set_{}", field.name);
+        let cfg = &field.cfg;
+        quote!{ #(#cfg)* w.#set_n(#fname); }
+    });
+
+    // Single merge of the collected writes, according to the storage of raw
+    let (recv, apply) = if params.atomic
+    {
+        let load = params.ordering.load_tokens();
+        let rmw = params.ordering.rmw_tokens();
+        (quote!{ self: &Self }, quote!{ self.raw.fetch_update(#rmw, #load, |raw| Some(w.merge(raw))).unwrap(); })
+    }
+    else if params.cell
+    {
+        (quote!{ self: &Self }, quote!{ self.raw.set(w.merge(self.raw.get())); })
+    }
+    else
+    {
+        (quote!{ self: &mut Self }, quote!{ self.raw = w.merge(self.raw); })
+    };
+
+    let doc = format!("Field writes collected by [`{}::update`], applied with a single read-modify-write", name);
+
+    quote!
+    {
+        #[doc = #doc]
+        #[derive(Clone, Copy, Default)]
+        #vis struct #writer
+        {
+            mask: #ty,
+            bits: #ty,
+        }
+
+        impl #writer
+        {
+            #(#setters)*
+
+            #(#clears)*
+
+            /// Value of raw once the collected writes are applied to `raw`
+            #[inline]
+            pub fn merge(self: &Self, raw: #ty) -> #ty
+            {
+                (raw & !self.mask) | self.bits
+            }
+        }
+
+        impl #impl_generics #name #ty_generics #where_clause
+        {
+            /// Writes the fields set by the closure, with a single read-modify-write of raw
+            #[inline]
+            pub fn update<F>(#recv, f: F)
+                where F: FnOnce(&mut #writer)
+            {
+                let mut w = #writer::default();
+                f(&mut w);
+                #apply
+            }
+
+            /// Writes every writable field at once, in declaration order, with a single read-modify-write of raw
+            /// The fields of the variants and the overlays are left out, as they share their bits: they are written with update()
+            #[inline]
+            #[allow(clippy::too_many_arguments)]
+            pub fn set_fields(#recv, #(#args),*)
+            {
+                let mut w = #writer::default();
+                #(#calls)*
+                #apply
+            }
+        }
+    }
+}
+
+fn generate_mmio(strukt: &Strukt, params: &MacroParams) -> TokenStream2
+{
+    if !params.mmio
+    {
+        return quote!{};
+    }
+
+    let vis = &strukt.vis;
+    let name = &strukt.name;
+    let reg = format_ident!("{}Reg", name);
+    let writer = format_ident!("{}Writer", name);
+    let raw_type = params.raw_tokens();
+    let generics = &strukt.generics;
+    let (impl_generics, ty_generics, where_clause) = strukt.generics.split_for_impl();
+    let (marker_decl, marker_init) = match strukt.phantom()
+    {
+        Some((decl, init)) => (quote!{ #decl, }, quote!{ #init, }),
+        None => (quote!{}, quote!{}),
+    };
+
+    let write_back = strukt.write_back_tokens(params, &quote!{ w });
+
+    // Batched field writes, with the 'update' parameter
+    let update = if params.update
+    {
+        quote!
+        {
+            /// Writes the fields set by the closure, with a single volatile read and a single volatile write
+            #[inline]
+            pub fn update<F>(self: &Self, f: F)
+                where F: FnOnce(&mut #writer)
+            {
+                self.modify(|_, w| w.update(f));
+            }
+        }
+    }
+    else
+    {
+        quote!{}
+    };
+
+    let doc = format!("Memory-mapped register holding a [`{}`], accessed with volatile reads and writes", name);
+
+    quote!
+    {
+        #[doc = #doc]
+        #[repr(transparent)]
+        #vis struct #reg #generics #where_clause
+        {
+            raw: core::cell::UnsafeCell<#raw_type>,
+            #marker_decl
+        }
+
+        impl #impl_generics #reg #ty_generics #where_clause
+        {
+            /// Register located at `ptr`
+            ///
+            /// # Safety
+            /// `ptr` must be valid for volatile reads and writes, and properly aligned, for the lifetime `'a`
+            pub unsafe fn from_ptr<'a>(ptr: *mut #raw_type) -> &'a Self
+            {
+                unsafe { &*(ptr as *const Self) }
+            }
+
+            /// Address of the register
+            pub fn as_ptr(self: &Self) -> *mut #raw_type
+            {
+                self.raw.get()
+            }
+
+            /// Snapshot of the register, with a single volatile read
+            #[inline]
+            pub fn read(self: &Self) -> #name #ty_generics
+            {
+                let raw = unsafe { core::ptr::read_volatile(self.raw.get()) };
+                #name { raw, #marker_init }
+            }
+
+            /// Writes the whole register, with a single volatile write
+            #[inline]
+            pub fn write(self: &Self, value: #name #ty_generics)
+            {
+                unsafe { core::ptr::write_volatile(self.raw.get(), value.raw) }
+            }
+
+            #update
+
+            /// Read-modify-write of the register, with a single volatile read and a single volatile write
+            /// The closure receives the value read, and the value to be written, initialized from the former
+            #[inline]
+            pub fn modify<F>(self: &Self, f: F)
+                where F: FnOnce(&#name #ty_generics, &mut #name #ty_generics)
+            {
+                let r = self.read();
+                let mut w = #name { raw: r.raw, #marker_init };
+                #write_back
+                f(&r, &mut w);
+                self.write(w);
+            }
+        }
+    }
+}
+
+fn generate_bus_access(strukt: &Strukt, params: &MacroParams) -> TokenStream2
+{
+    let bus = match &params.bus
+    {
+        Some(bus) => bus,
+        None => return quote!{},
+    };
+
+    let name = &strukt.name;
+    let (impl_generics, ty_generics, where_clause) = strukt.generics.split_for_impl();
+    let elem = &params.ty;
+    let bits = params.elem_size() as u32;
+
+    // Bitfields backed by an array are accessed one element after the other
+    let (read, write) = match params.array_len
+    {
+        Some(len) =>
+        {
+            let step = (params.elem_size() / 8) as u64;
+            (quote!
+            {
+                let mut raw = [0 as #elem; #len];
+                for (i, e) in raw.iter_mut().enumerate()
+                {
+                    *e = bus.read(addr + i as u64 * #step, #bits) as #elem;
+                }
+                raw
+            },
+            quote!
+            {
+                for (i, e) in self.raw.iter().enumerate()
+                {
+                    bus.write(addr + i as u64 * #step, #bits, *e as u128);
+                }
+            })
+        },
+        None => (quote!{ bus.read(addr, #bits) as #elem }, quote!{ bus.write(addr, #bits, self.raw as u128); }),
+    };
+
+    let from_bus = strukt.construct_tokens(params, &quote!{ { #read } });
+    let copy = strukt.construct_tokens(params, &quote!{ r.raw });
+    let write_back = strukt.write_back_tokens(params, &quote!{ w });
+
+    quote!
+    {
+        impl #impl_generics #name #ty_generics #where_clause
+        {
+            /// Reads the bitfield at `addr` through a bus
+            pub fn read_from<B: #bus + ?Sized>(bus: &mut B, addr: u64) -> Self
+            {
+                #from_bus
+            }
+
+            /// Writes the bitfield at `addr` through a bus
+            pub fn write_to<B: #bus + ?Sized>(self: &Self, bus: &mut B, addr: u64)
+            {
+                #write
+            }
+
+            /// Read-modify-write of the bitfield at `addr` through a bus
+            /// The closure receives the value read, and the value to be written, initialized from the former
+            pub fn modify_on<B, F>(bus: &mut B, addr: u64, f: F)
+                where B: #bus + ?Sized, F: FnOnce(&Self, &mut Self)
+            {
+                let r = Self::read_from(bus, addr);
+                let mut w = #copy;
+                #write_back
+                f(&r, &mut w);
+                w.write_to(bus, addr);
+            }
+        }
+    }
+}
+
+// Views over the fields of each variant of a tagged layout, and the variant() accessor selecting them from the tag
+fn generate_variants(strukt: &Strukt, params: &MacroParams) -> TokenStream2
+{
+    let fields: Vec<&BitField> = strukt.accessors().filter(|f| f.variant.is_some()).collect();
+    let tag = match strukt.variant_tag()
+    {
+        Some(tag) => tag,
+        None => return quote!{},
+    };
+
+    let vis = &strukt.vis;
+    let name = &strukt.name;
+    let variant_enum = format_ident!("{}Variant", name);
+    let (impl_generics, ty_generics, where_clause) = strukt.generics.split_for_impl();
+    // The views borrow the structure
+    let mut view_generics = strukt.generics.clone();
+    view_generics.params.insert(0, syn::parse_quote!{ 'bitf });
+    let (view_impl_generics, view_ty_generics, view_where_clause) = view_generics.split_for_impl();
+    let tag_value = tag.value_tokens(params, &quote!{ self });
+
+    // Variants in declaration order
+    let mut variants: Vec<&bitfield::Variant> = Vec::new();
+    for f in &fields
+    {
+        let v = f.variant.as_ref().unwrap();
+        if !variants.iter().any(|o| o.name == v.name)
+        {
+            variants.push(v);
+        }
+    }
+
+    let views = variants.iter().map(|v|
+    {
+        let view = format_ident!("{}{}", name, v.name);
+        let doc = format!("View of the fields of the variant `{}` of [`{}`]", v.name, name);
+        let getters = fields.iter()
+                            .filter(|f| f.policy.readable() && f.variant.as_ref().unwrap().name == v.name)
+                            .map(|f|
+                                 {
+                                     let fname = format_ident!("{}", f.name);
+                                     let fvis = &f.vis;
+                                     let docs = &f.docs;
+                                     let ty = f.getter_type(params);
+                                     quote!
+                                     {
+                                         #(#docs)*
+                                         #[inline]
+                                         #[allow(non_snake_case)]
+                                         #fvis fn #fname(self: &Self) -> #ty
+                                         {
+                                             self.0.#fname()
+                                         }
+                                     }
+                                 });
+
+        quote!
+        {
+            #[doc = #doc]
+            #vis struct #view #view_generics (pub &'bitf #name #ty_generics) #view_where_clause;
+
+            impl #view_impl_generics #view #view_ty_generics #view_where_clause
+            {
+                #(#getters)*
+            }
+        }
+    });
+
+    let enum_variants = variants.iter().map(|v|
+    {
+        let vname = &v.name;
+        let view = format_ident!("{}{}", name, v.name);
+        quote!{ #vname(#view #view_ty_generics), }
+    });
+    let selects = variants.iter().map(|v|
+    {
+        let vname = &v.name;
+        let value = v.tag_tokens();
+        let view = format_ident!("{}{}", name, v.name);
+        quote!
+        {
+            if tag == (#value)
+            {
+                return Some(#variant_enum::#vname(#view(self)));
+            }
+        }
+    });
+
+    let doc = format!("Variants of [`{}`], selected by the field `{}`", name, tag.name);
+
+    quote!
+    {
+        #(#views)*
+
+        #[doc = #doc]
+        #vis enum #variant_enum #view_generics #view_where_clause
+        {
+            #(#enum_variants)*
+        }
+
+        impl #impl_generics #name #ty_generics #where_clause
+        {
+            /// View of the fields of the variant selected by the tag, or None when no variant is declared for its value
+            pub fn variant<'bitf>(self: &'bitf Self) -> Option<#variant_enum #view_ty_generics>
+            {
+                let tag = #tag_value;
+                #(#selects)*
+                None
+            }
+        }
+    }
+}
+
+// Enum of the fields and iterator over their values, for accesses to fields chosen at runtime
+// Fields wider than 128 bits are left out
+fn generate_field_enum(strukt: &Strukt, bitfields: &[BitField], params: &MacroParams) -> TokenStream2
+{
+    if !params.dynamic
+    {
+        return quote!{};
+    }
+
+    let vis = &strukt.vis;
+    let name = &strukt.name;
+    let field_enum = format_ident!("{}Field", name);
+    let (impl_generics, ty_generics, where_clause) = strukt.generics.split_for_impl();
+    let recv = quote!{ self };
+    // Type of the values, the smallest primitive holding every field when backed by an array
+    let ty = if params.is_array() { quote!{ u128 } } else { let ty = &params.ty; quote!{ #ty } };
+    let mut_recv = params.setter_receiver();
+
+    // Write-only fields are left out, as they have no getter
+    let fields: Vec<&BitField> = bitfields.iter().filter(|f| f.bsize <= 128 && f.policy.readable()).collect();
+
+    let variants = fields.iter().map(|f|
+    {
+        let cfg = &f.cfg;
+        let docs = &f.docs;
+        let variant = f.variant_name();
+        let range_doc = f.range_doc();
+        let sep = if docs.is_empty() { quote!{} } else { quote!{ #[doc = ""] } };
+        quote!{ #(#cfg)* #(#docs)* #sep #[doc = #range_doc] #variant, }
+    });
+    let names = fields.iter().map(|f|
+    {
+        let cfg = &f.cfg;
+        let fname = &f.name;
+        let variant = f.variant_name();
+        quote!{ #(#cfg)* #field_enum::#variant => #fname, }
+    });
+    let by_name = fields.iter().map(|f|
+    {
+        let cfg = &f.cfg;
+        let fname = &f.name;
+        let variant = f.variant_name();
+        quote!{ #(#cfg)* #fname => Some(#field_enum::#variant), }
+    });
+    let gets = fields.iter().map(|f|
+    {
+        let cfg = &f.cfg;
+        let variant = f.variant_name();
+        let v = f.value_tokens(params, &recv);
+        quote!{ #(#cfg)* #field_enum::#variant => #v as #ty, }
+    });
+    // Disabled conditional fields are left out when iterating, as well as overlays which repeat the bits of their host
+    let values = fields.iter().filter(|f| !f.overlay).map(|f|
+    {
+        let fname = &f.name;
+        let v = f.value_tokens(params, &recv);
+        match f.cfg_predicate()
+        {
+            Some(pred) => quote!{ if cfg!(#pred) { Some((#fname, #v as #ty)) } else { None } },
+            None => quote!{ Some((#fname, #v as #ty)) },
+        }
+    });
+    let sets = fields.iter().map(|f|
+    {
+        let cfg = &f.cfg;
+        let variant = f.variant_name();
+        if !f.policy.writable()
+        {
+            return quote!{ #(#cfg)* #field_enum::#variant => false, };
+        }
+
+        let vt = f.value_type(params);
+        let ones = f.ones_tokens(params);
+        let store = f.store_tokens(params, &recv, &quote!{ val });
+        quote!
+        {
+            #(#cfg)*
+            #field_enum::#variant =>
+            {
+                if val > #ones as #ty
+                {
+                    return false;
+                }
+                let val = val as #vt;
+                #store
+                true
+            },
+        }
+    });
+
+    let doc = format!("Fields of [`{}`], for accesses to fields chosen at runtime", name);
+
+    quote!
+    {
+        #[doc = #doc]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        #vis enum #field_enum
+        {
+            #(#variants)*
+        }
+
+        impl #field_enum
+        {
+            /// Name of the field, as declared
+            pub fn name(self: &Self) -> &'static str
+            {
+                match *self
+                {
+                    #(#names)*
+                }
+            }
+        }
+
+        impl #impl_generics #name #ty_generics #where_clause
+        {
+            /// Field declared with the given name
+            pub fn field_by_name(name: &str) -> Option<#field_enum>
+            {
+                match name
+                {
+                    #(#by_name)*
+                    _ => None,
+                }
+            }
+
+            /// Value of the given field
+            #[allow(clippy::unnecessary_cast)]
+            pub fn get(self: &Self, field: #field_enum) -> #ty
+            {
+                match field
+                {
+                    #(#gets)*
+                }
+            }
+
+            /// Names and values of the fields, in declaration order
+            #[allow(clippy::unnecessary_cast)]
+            pub fn fields(self: &Self) -> impl Iterator<Item = (&'static str, #ty)>
+            {
+                [#(#values),*].into_iter().flatten()
+            }
+
+            /// Names of the fields whose values differ in `other`, with the values of `self` and `other`
+            pub fn diff(self: &Self, other: &Self) -> impl Iterator<Item = (&'static str, #ty, #ty)>
+            {
+                self.fields()
+                    .zip(other.fields())
+                    .filter(|(a, b)| a.1 != b.1)
+                    .map(|((name, old), (_, new))| (name, old, new))
+            }
+
+            /// Writes the given field
+            /// Returns false, leaving the bitfield unchanged, when the field is not writable or the value does not fit in it
+            #[allow(clippy::unnecessary_cast)]
+            pub fn set(#mut_recv, field: #field_enum, val: #ty) -> bool
+            {
+                match field
+                {
+                    #(#sets)*
+                }
+            }
+        }
+    }
+}
+
+// Matching of the bitfield against (value, mask) patterns, where the bits cleared in the mask are don't-care
+// Bitfields backed by an array do not implement it
+fn generate_patterns(strukt: &Strukt, bitfields: &[BitField], params: &MacroParams) -> TokenStream2
+{
+    if !params.pattern
+    {
+        return quote!{};
+    }
+
+    let vis = &strukt.vis;
+    let name = &strukt.name;
+    let pattern = format_ident!("{}Pattern", name);
+    let (impl_generics, ty_generics, where_clause) = strukt.generics.split_for_impl();
+    let ty = &params.ty;
+    let size = params.bitfield_size;
+    let raw = params.load_tokens(&quote!{ self });
+    // Loads of atomic and Cell bitfields cannot be evaluated in const contexts
+    let constness = if params.is_shared() { quote!{} } else { quote!{ const } };
+
+    let builders = bitfields.iter().map(|f|
+    {
+        let cfg = &f.cfg;
+        let docs = &f.docs;
+        let fname = format_ident!("{}", f.name);
+        let range_doc = f.range_doc();
+        let sep = if docs.is_empty() { quote!{} } else { quote!{ #[doc = ""] } };
+        let fpos = f.pos_tokens();
+        let mask = f.mask_tokens(params);
+        let ones = f.ones_tokens(params);
+        let overflow = format!("Value of field `{}` does not fit in its width", f.name);
+
+        quote!
+        {
+            #(#cfg)*
+            #(#docs)*
+            #sep
+            #[doc = #range_doc]
+            #[inline]
+            #[allow(non_snake_case)]
+            pub const fn #fname(self, val: #ty) -> Self
+            {
+                assert!(val <= #ones, #overflow);
+                let mask = #mask;
+                #pattern { value: (self.value & !mask) | (val << #fpos), mask: self.mask | mask }
+            }
+        }
+    });
+
+    let doc = format!("Pattern of [`{}`] built field by field, the fields not given being don't-care", name);
+
+    quote!
+    {
+        #[doc = #doc]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        #vis struct #pattern
+        {
+            pub value: #ty,
+            pub mask: #ty,
+        }
+
+        impl #pattern
+        {
+            #(#builders)*
+        }
+
+        impl #impl_generics #name #ty_generics #where_clause
+        {
+            /// True if the bits set in `mask` are equal in the bitfield and in `value`
+            #[inline]
+            pub #constness fn matches(self: &Self, value: #ty, mask: #ty) -> bool
+            {
+                (#raw & mask) == (value & mask)
+            }
+
+            /// True if the fields given to the pattern are equal in the bitfield
+            #[inline]
+            pub #constness fn matches_pattern(self: &Self, pattern: #pattern) -> bool
+            {
+                self.matches(pattern.value, pattern.mask)
+            }
+
+            /// Pattern matching any value, to be restricted field by field
+            pub const fn pattern() -> #pattern
+            {
+                #pattern { value: 0, mask: 0 }
+            }
+
+            /// (value, mask) pair of a pattern such as "0000_10xx", most significant bit first
+            /// `x` marks a don't-care bit, `_` and spaces are ignored, and the pattern must give every bit of the bitfield
+            pub const fn from_pattern(pattern: &str) -> (#ty, #ty)
+            {
+                let bytes = pattern.as_bytes();
+                let mut value: #ty = 0;
+                let mut mask: #ty = 0;
+                let mut bits = 0;
+                let mut i = 0;
+
+                while i < bytes.len()
+                {
+                    match bytes[i]
+                    {
+                        b'0' | b'1' | b'x' | b'X' =>
+                        {
+                            assert!(bits < #size, "Pattern longer than the bitfield");
+                            value = (value << 1) | (bytes[i] == b'1') as #ty;
+                            mask = (mask << 1) | (bytes[i] == b'0' || bytes[i] == b'1') as #ty;
+                            bits += 1;
+                        },
+                        b'_' | b' ' => (),
+                        _ => panic!("Invalid character in pattern, expected '0', '1', 'x' or '_'"),
+                    }
+                    i += 1;
+                }
+
+                assert!(bits == #size, "Pattern shorter than the bitfield");
+                (value, mask)
+            }
+        }
+    }
+}
+
+// Report of the fields differing from an expected value, used by assert_bitf_eq!
+// The report is displayed without allocating, so that it is available in no_std crates
+fn generate_mismatch_report(strukt: &Strukt, params: &MacroParams) -> TokenStream2
+{
+    if !params.assert
+    {
+        return quote!{};
+    }
+
+    let vis = &strukt.vis;
+    let name = &strukt.name;
+    let mismatch = format_ident!("{}Mismatch", name);
+    let (impl_generics, ty_generics, where_clause) = strukt.generics.split_for_impl();
+    // The report borrows both values
+    let mut report_generics = strukt.generics.clone();
+    report_generics.params.insert(0, syn::parse_quote!{ 'bitf });
+    let (report_impl_generics, report_ty_generics, report_where_clause) = report_generics.split_for_impl();
+    let expected = quote!{ self.expected };
+    let actual = quote!{ self.actual };
+
+    let rows = strukt.reported().map(|f|
+    {
+        let cfg = &f.cfg;
+        let fname = &f.name;
+        let e = f.value_tokens(params, &expected);
+        let a = f.value_tokens(params, &actual);
+        // Fields wider than 128 bits are arrays of bytes
+        let format = if f.bsize > 128 { quote!{ "{:02x?}" } } else { quote!{ "{:#x}" } };
+        quote!
+        {
+            #(#cfg)*
+            {
+                let (e, a) = (#e, #a);
+                if e != a
+                {
+                    row(#fname, format_args!(#format, e), format_args!(#format, a))?;
+                }
+            }
+        }
+    });
+
+    let diagrams = if params.pprint
+    {
+        quote!
+        {
+            write!(f, "\n{}", self.expected.pprint_render(Some(self.actual)))?;
+        }
+    }
+    else
+    {
+        quote!{}
+    };
+
+    let doc = format!("Fields of a [`{}`] differing from the expected value, displayed as a table of the expected and actual values", name);
+
+    quote!
+    {
+        #[doc = #doc]
+        #vis struct #mismatch #report_generics #report_where_clause
+        {
+            pub expected:   &'bitf #name #ty_generics,
+            pub actual:     &'bitf #name #ty_generics,
+        }
+
+        impl #report_impl_generics #mismatch #report_ty_generics #report_where_clause
+        {
+            // Calls `row` with the name, the expected and the actual value of each differing field
+            fn visit(self: &Self, row: &mut dyn FnMut(&str, core::fmt::Arguments<'_>, core::fmt::Arguments<'_>) -> core::fmt::Result) -> core::fmt::Result
+            {
+                #(#rows)*
+                Ok(())
+            }
+        }
+
+        impl #report_impl_generics core::fmt::Display for #mismatch #report_ty_generics #report_where_clause
+        {
+            fn fmt(self: &Self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result
+            {
+                // Counts the characters written, to measure the columns
+                struct Width(usize);
+                impl core::fmt::Write for Width
+                {
+                    fn write_str(&mut self, s: &str) -> core::fmt::Result
+                    {
+                        self.0 += s.chars().count();
+                        Ok(())
+                    }
+                }
+                let width = |args: core::fmt::Arguments<'_>|
+                {
+                    let mut w = Width(0);
+                    let _ = core::fmt::write(&mut w, args);
+                    w.0
+                };
+
+                let (mut w0, mut w1, mut w2) = ("field".len(), "expected".len(), "actual".len());
+                self.visit(&mut |field, e, a|
+                {
+                    w0 = w0.max(field.len());
+                    w1 = w1.max(width(e));
+                    w2 = w2.max(width(a));
+                    Ok(())
+                })?;
+
+                writeln!(f, "{:<w0$}  {:>w1$}  {:>w2$}", "field", "expected", "actual")?;
+                // Arguments ignore the width of the format, the values are padded by hand
+                self.visit(&mut |field, e, a|
+                {
+                    writeln!(f, "{:<w0$}  {:>pe$}{}  {:>pa$}{}", field, "", e, "", a, pe = w1 - width(e), pa = w2 - width(a))
+                })?;
+                #diagrams
+
+                Ok(())
+            }
+        }
+
+        impl #impl_generics #name #ty_generics #where_clause
+        {
+            /// Report of the fields whose values differ from `expected`, or None when every field is equal
+            pub fn mismatch_report<'bitf>(self: &'bitf Self, expected: &'bitf Self) -> Option<#mismatch #report_ty_generics>
+            {
+                let report = #mismatch { expected, actual: self };
+                // The visit stops at the first differing field
+                let mut differs = false;
+                let _ = report.visit(&mut |_, _, _|
+                {
+                    differs = true;
+                    Err(core::fmt::Error)
+                });
+
+                if differs { Some(report) } else { None }
+            }
+        }
+    }
+}
+
+// Serialize and Deserialize implementations, as the value of raw or as a map of the fields
+fn generate_serde(strukt: &Strukt, params: &MacroParams) -> TokenStream2
+{
+    let repr = match &params.serde
+    {
+        Some(repr) => repr,
+        None => return quote!{},
+    };
+
+    let name = &strukt.name;
+    let name_str = name.to_string();
+    let (impl_generics, ty_generics, where_clause) = strukt.generics.split_for_impl();
+    // Deserialize takes an additional 'de lifetime
+    let mut de_generics = strukt.generics.clone();
+    de_generics.params.insert(0, syn::parse_quote!{ 'de });
+    let (de_impl_generics, _, _) = de_generics.split_for_impl();
+    let this = quote!{ self };
+
+    let (serialize, deserialize) = match repr
+    {
+        SerdeRepr::Raw =>
+        {
+            let raw = params.load_tokens(&this);
+            let value = params.value_tokens();
+            let construct = strukt.construct_tokens(params, &quote!{ raw });
+
+            (quote!
+            {
+                serde::Serialize::serialize(&#raw, serializer)
+            },
+            quote!
+            {
+                let raw = <#value as serde::Deserialize>::deserialize(deserializer)?;
+                Ok(#construct)
+            })
+        },
+        SerdeRepr::Fields =>
+        {
+            let fields: Vec<&BitField> = strukt.reported().collect();
+            let names: Vec<&String> = fields.iter().map(|f| &f.name).collect();
+            // The fields of a variant are only represented when the tag selects their variant
+            let tag = strukt.variant_tag();
+            let selected = |f: &BitField| f.variant.as_ref().map(|v| { let value = v.tag_tokens(); quote!{ tag == #value } });
+            let tag_load = match tag
+            {
+                Some(tag) => { let v = tag.value_tokens(params, &this); quote!{ let tag = #v; } },
+                None => quote!{},
+            };
+            // Number of fields enabled by their #[cfg(...)] and by the tag
+            let count = fields.iter().map(|f| match (f.cfg_predicate(), selected(f))
+            {
+                (Some(pred), Some(sel)) => quote!{ + (cfg!(#pred) && #sel) as usize },
+                (Some(pred), None) => quote!{ + cfg!(#pred) as usize },
+                (None, Some(sel)) => quote!{ + (#sel) as usize },
+                (None, None) => quote!{ + 1 },
+            });
+            let serialized = fields.iter().map(|f|
+            {
+                let cfg = &f.cfg;
+                let fname = &f.name;
+                let v = f.value_tokens(params, &this);
+                let serialize = if f.bsize > 128
+                {
+                    quote!{ st.serialize_field(#fname, &__Bytes(&#v))?; }
+                }
+                else
+                {
+                    quote!{ st.serialize_field(#fname, &#v)?; }
+                };
+
+                match selected(f)
+                {
+                    Some(sel) => quote!{ #(#cfg)* if #sel { #serialize } },
+                    None => quote!{ #(#cfg)* { #serialize } },
+                }
+            });
+            // Fields wider than 128 bits are arrays of bytes, which serde only supports up to 32 elements:
+            // they are (de)serialized as sequences of bytes instead
+            let (ser_bytes, de_bytes) = if fields.iter().any(|f| f.bsize > 128)
+            {
+                (quote!
+                {
+                    struct __Bytes<'a>(&'a [u8]);
+
+                    impl serde::Serialize for __Bytes<'_>
+                    {
+                        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+                            where S: serde::Serializer
+                        {
+                            serializer.serialize_bytes(self.0)
+                        }
+                    }
+                },
+                quote!
+                {
+                    struct __Bytes<const N: usize>([u8; N]);
+
+                    impl<'de, const N: usize> serde::Deserialize<'de> for __Bytes<N>
+                    {
+                        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+                            where D: serde::Deserializer<'de>
+                        {
+                            struct __BytesVisitor<const N: usize>;
+
+                            impl<'de, const N: usize> serde::de::Visitor<'de> for __BytesVisitor<N>
+                            {
+                                type Value = [u8; N];
+
+                                fn expecting(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result
+                                {
+                                    write!(f, "{} bytes", N)
+                                }
+
+                                fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+                                    where E: serde::de::Error
+                                {
+                                    <[u8; N] as core::convert::TryFrom<&[u8]>>::try_from(v).map_err(|_| E::invalid_length(v.len(), &self))
+                                }
+
+                                fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+                                    where A: serde::de::SeqAccess<'de>
+                                {
+                                    let mut bytes = [0u8; N];
+                                    for (i, b) in bytes.iter_mut().enumerate()
+                                    {
+                                        *b = seq.next_element()?.ok_or_else(|| serde::de::Error::invalid_length(i, &self))?;
+                                    }
+
+                                    if seq.next_element::<u8>()?.is_some()
+                                    {
+                                        return Err(serde::de::Error::invalid_length(N + 1, &self));
+                                    }
+
+                                    Ok(bytes)
+                                }
+                            }
+
+                            deserializer.deserialize_bytes(__BytesVisitor::<N>).map(__Bytes)
+                        }
+                    }
+                })
+            }
+            else
+            {
+                (quote!{}, quote!{})
+            };
+
+            let vars: Vec<Ident> = fields.iter().map(|f| format_ident!("__field_{}", f.name)).collect();
+            let decls = fields.iter().zip(&vars).map(|(f, var)|
+            {
+                let cfg = &f.cfg;
+                let vt = f.value_type(params);
+                quote!{ #(#cfg)* let mut #var: Option<#vt> = None; }
+            });
+            let arms = fields.iter().zip(&vars).map(|(f, var)|
+            {
+                let cfg = &f.cfg;
+                let fname = &f.name;
+                let fsize = f.bsize;
+                let vt = f.value_type(params);
+                let ones = f.ones_tokens(params);
+                let next = if fsize > 128
+                {
+                    let len = fsize.div_ceil(8);
+                    quote!{ map.next_value::<__Bytes<#len>>()?.0 }
+                }
+                else
+                {
+                    quote!{ map.next_value()? }
+                };
+                // Values wider than the field are rejected
+                let overflow = if fsize > 128 && fsize % 8 == 0
+                {
+                    quote!{}
+                }
+                else
+                {
+                    let cond = if fsize > 128
+                    {
+                        let last = fsize.div_ceil(8) - 1;
+                        let used = (fsize % 8) as u32;
+                        quote!{ (v[#last] >> #used) != 0 }
+                    }
+                    else
+                    {
+                        quote!{ v > #ones }
+                    };
+
+                    quote!
+                    {
+                        if #cond
+                        {
+                            return Err(serde::de::Error::custom(format_args!("value of field `{}` overflows its {} bits", #fname, #fsize)));
+                        }
+                    }
+                };
+
+                quote!
+                {
+                    #(#cfg)*
+                    #fname =>
+                    {
+                        if #var.is_some()
+                        {
+                            return Err(serde::de::Error::duplicate_field(#fname));
+                        }
+                        let v: #vt = #next;
+                        #overflow
+                        #var = Some(v);
+                    },
+                }
+            });
+            let stores = fields.iter().zip(&vars).map(|(f, var)|
+            {
+                let cfg = &f.cfg;
+                let fname = &f.name;
+                let store = f.store_tokens(params, &quote!{ ret }, &quote!{ val });
+                // The fields of the variants not selected by the tag must be left out
+                match (selected(f), tag)
+                {
+                    (Some(sel), Some(tag)) =>
+                    {
+                        let msg = format!("field `{}` is not part of the variant selected by `{}`", f.name, tag.name);
+                        quote!
+                        {
+                            #(#cfg)*
+                            if #sel
+                            {
+                                let val = #var.ok_or_else(|| serde::de::Error::missing_field(#fname))?;
+                                #store
+                            }
+                            else if #var.is_some()
+                            {
+                                return Err(serde::de::Error::custom(#msg));
+                            }
+                        }
+                    },
+                    _ => quote!
+                    {
+                        #(#cfg)*
+                        {
+                            let val = #var.ok_or_else(|| serde::de::Error::missing_field(#fname))?;
+                            #store
+                        }
+                    },
+                }
+            });
+            let tag_var = match tag
+            {
+                Some(tag) =>
+                {
+                    let var = format_ident!("__field_{}", tag.name);
+                    let tname = &tag.name;
+                    quote!{ let tag = #var.ok_or_else(|| serde::de::Error::missing_field(#tname))?; }
+                },
+                None => quote!{},
+            };
+            let mutability = params.binding_mutability();
+            let expecting = format!("a map of the fields of {}", name);
+
+            (quote!
+            {
+                use serde::ser::SerializeStruct;
+
+                #ser_bytes
+
+                #tag_load
+                let len: usize = 0 #(#count)*;
+                let mut st = serializer.serialize_struct(#name_str, len)?;
+                #(#serialized)*
+                st.end()
+            },
+            quote!
+            {
+                const FIELDS: &[&str] = &[#(#names),*];
+
+                #de_bytes
+
+                struct __Visitor<__T>(core::marker::PhantomData<__T>);
+
+                impl #de_impl_generics serde::de::Visitor<'de> for __Visitor<#name #ty_generics> #where_clause
+                {
+                    type Value = #name #ty_generics;
+
+                    fn expecting(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result
+                    {
+                        f.write_str(#expecting)
+                    }
+
+                    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+                        where A: serde::de::MapAccess<'de>
+                    {
+                        #(#decls)*
+
+                        while let Some(key) = map.next_key::<String>()?
+                        {
+                            match key.as_str()
+                            {
+                                #(#arms)*
+                                other => return Err(serde::de::Error::unknown_field(other, FIELDS)),
+                            }
+                        }
+
+                        let #mutability ret = <Self::Value as Default>::default();
+                        #tag_var
+                        #(#stores)*
+                        Ok(ret)
+                    }
+                }
+
+                deserializer.deserialize_struct(#name_str, FIELDS, __Visitor(core::marker::PhantomData))
+            })
+        },
+    };
+
+    quote!
+    {
+        impl #impl_generics serde::Serialize for #name #ty_generics #where_clause
+        {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+                where S: serde::Serializer
+            {
+                #serialize
+            }
+        }
+
+        impl #de_impl_generics serde::Deserialize<'de> for #name #ty_generics #where_clause
+        {
+            fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+                where D: serde::Deserializer<'de>
+            {
+                #deserialize
+            }
+        }
+    }
+}
+
+fn generate_derives(strukt: &Strukt, derives: &[syn::Path], params: &MacroParams) -> TokenStream2
+{
+    let name = &strukt.name;
+    let (impl_generics, ty_generics, where_clause) = strukt.generics.split_for_impl();
+    let this = quote!{ self };
+    let other = quote!{ other };
+
+    // Values of every implemented field, reserved and unused bits are ignored
+    let compared: Vec<TokenStream2> = strukt.reported()
+                                            .map(|f|
+                                                 {
+                                                     let cfg = &f.cfg;
+                                                     let a = f.value_tokens(params, &this);
+                                                     let b = f.value_tokens(params, &other);
+                                                     quote!{ #(#cfg)* { if #a != #b { return false; } } }
+                                                 })
+                                            .collect();
+    let hashed: Vec<TokenStream2> = strukt.reported()
+                                          .map(|f|
+                                               {
+                                                   let cfg = &f.cfg;
+                                                   let v = f.value_tokens(params, &this);
+                                                   quote!{ #(#cfg)* { #v.hash(state); } }
+                                               })
+                                          .collect();
+
+    let mut impls = Vec::new();
+    for derive in derives
+    {
+        let trait_name = derive.segments.last().unwrap().ident.to_string();
+        match trait_name.as_ref()
+        {
+            "Debug" =>
+            {
+                let name_str = name.to_string();
+                let fields: Vec<TokenStream2> = strukt.reported()
+                                                      .map(|f|
+                                                           {
+                                                               let cfg = &f.cfg;
+                                                               let fname = &f.name;
+                                                               let v = f.value_tokens(params, &this);
+                                                               quote!{ #(#cfg)* { dbg.field(#fname, &#v); } }
+                                                           })
+                                                      .collect();
+
+                impls.push(quote!
+                {
+                    impl #impl_generics core::fmt::Debug for #name #ty_generics #where_clause
+                    {
+                        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result
+                        {
+                            let mut dbg = f.debug_struct(#name_str);
+                            #(#fields)*
+                            dbg.finish()
+                        }
+                    }
+                });
+            },
+            "PartialEq" =>
+            {
+                impls.push(quote!
+                {
+                    impl #impl_generics PartialEq for #name #ty_generics #where_clause
+                    {
+                        fn eq(&self, other: &Self) -> bool
+                        {
+                            #(#compared)*
+                            true
+                        }
+                    }
+                });
+            },
+            "Hash" =>
+            {
+                impls.push(quote!
+                {
+                    impl #impl_generics core::hash::Hash for #name #ty_generics #where_clause
+                    {
+                        fn hash<H: core::hash::Hasher>(&self, state: &mut H)
+                        {
+                            #(#hashed)*
+                        }
+                    }
+                });
+            },
+            // Comma separated `field=value` entries, such as `enable=1,mode=3`
+            "Display" =>
+            {
+                let entries: Vec<TokenStream2> = strukt.reported()
+                                                       .map(|f|
+                                                            {
+                                                                let cfg = &f.cfg;
+                                                                let fname = &f.name;
+                                                                let v = f.value_tokens(params, &this);
+                                                                // Fields wider than 128 bits are printed in hex, most significant byte first
+                                                                let write = if f.bsize > 128
+                                                                {
+                                                                    quote!
+                                                                    {
+                                                                        write!(f, "{}{}=0x", sep, #fname)?;
+                                                                        for b in #v.iter().rev()
+                                                                        {
+                                                                            write!(f, "{:02x}", b)?;
+                                                                        }
+                                                                    }
+                                                                }
+                                                                else
+                                                                {
+                                                                    quote!{ write!(f, "{}{}={}", sep, #fname, #v)?; }
+                                                                };
+                                                                quote!{ #(#cfg)* { #write sep = ","; } }
+                                                            })
+                                                       .collect();
+
+                impls.push(quote!
+                {
+                    impl #impl_generics core::fmt::Display for #name #ty_generics #where_clause
+                    {
+                        #[allow(unused_assignments)]
+                        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result
+                        {
+                            let mut sep = "";
+                            #(#entries)*
+                            Ok(())
+                        }
+                    }
+                });
+            },
+            "FromStr" =>
+            {
+                impls.push(generate_from_str(strukt, params));
+            },
+            _ => (),
+        }
+    }
+
+    quote!{ #(#impls)* }
+}
+
+// Parsing of the `field=value` format printed by Display, into a structure whose unspecified fields are zero
+fn generate_from_str(strukt: &Strukt, params: &MacroParams) -> TokenStream2
+{
+    let vis = &strukt.vis;
+    let name = &strukt.name;
+    let err = format_ident!("{}ParseError", name);
+    let entry = format_ident!("{}ParseEntry", name);
+    let (impl_generics, ty_generics, where_clause) = strukt.generics.split_for_impl();
+    let mutability = params.binding_mutability();
+
+    let arms = strukt.reported().map(|f|
+    {
+        let cfg = &f.cfg;
+        let fname = &f.name;
+        let fsize = f.bsize;
+        let vt = f.value_type(params);
+        let store = f.store_tokens(params, &quote!{ ret }, &quote!{ val });
+
+        // Fields wider than 128 bits are filled from their least significant byte
+        let parse = if fsize > 128
+        {
+            let len = fsize.div_ceil(8);
+            let top = fsize - (len - 1) * 8;
+            // The bits of the last byte above the width of the field must be clear
+            let check = if top < 8
+            {
+                quote!
+                {
+                    if val[#len - 1] >> #top != 0
+                    {
+                        return Err(#err::Overflow(#fname));
+                    }
+                }
+            }
+            else
+            {
+                quote!{}
+            };
+
+            quote!
+            {
+                let val = parse_bytes::<#len>(#fname, value)?;
+                #check
+            }
+        }
+        else
+        {
+            let ones = f.ones_tokens(params);
+            quote!
+            {
+                let val = parse_value(#fname, value)?;
+                if val > #ones as u128
+                {
+                    return Err(#err::Overflow(#fname));
+                }
+                let val = val as #vt;
+            }
+        };
+
+        quote!
+        {
+            #(#cfg)*
+            #fname =>
+            {
+                #parse
+                #store
+            },
+        }
+    });
+
+    quote!
+    {
+        /// Text of an entry in error, with its range of bytes in the parsed string
+        /// The text is copied without allocating, and truncated to its first 32 bytes
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        #vis struct #entry
+        {
+            text:   [u8; 32],
+            len:    usize,
+            range:  core::ops::Range<usize>,
+        }
+
+        impl #entry
+        {
+            fn new(text: &str, at: usize) -> Self
+            {
+                // Truncate on a character boundary
+                let mut len = text.len().min(32);
+                while !text.is_char_boundary(len)
+                {
+                    len -= 1;
+                }
+
+                let mut copy = [0u8; 32];
+                copy[..len].copy_from_slice(&text.as_bytes()[..len]);
+                #entry { text: copy, len, range: at..at + text.len() }
+            }
+
+            /// Text of the entry, possibly truncated
+            pub fn as_str(self: &Self) -> &str
+            {
+                core::str::from_utf8(&self.text[..self.len]).unwrap_or_default()
+            }
+
+            /// Range of bytes of the entry in the parsed string
+            pub fn range(self: &Self) -> core::ops::Range<usize>
+            {
+                self.range.clone()
+            }
+        }
+
+        impl core::fmt::Display for #entry
+        {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result
+            {
+                let ellipsis = if self.len < self.range.len() { "..." } else { "" };
+                write!(f, "`{}{}` at bytes {}..{}", self.as_str(), ellipsis, self.range.start, self.range.end)
+            }
+        }
+
+        /// Error returned when parsing a bitfield from its `field=value` representation
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        #vis enum #err
+        {
+            /// An entry is not of the form `field=value`
+            Syntax(#entry),
+            /// No field has the given name
+            UnknownField(#entry),
+            /// The value of the field is not a decimal, hex or binary literal
+            InvalidValue(&'static str),
+            /// The value does not fit in the width of the field
+            Overflow(&'static str),
+        }
+
+        impl core::fmt::Display for #err
+        {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result
+            {
+                match self
+                {
+                    #err::Syntax(entry) => write!(f, "expected `field=value`, found {}", entry),
+                    #err::UnknownField(field) => write!(f, "unknown field {}", field),
+                    #err::InvalidValue(field) => write!(f, "invalid value for field `{}`", field),
+                    #err::Overflow(field) => write!(f, "value of field `{}` does not fit in its width", field),
+                }
+            }
+        }
+
+        impl core::error::Error for #err {}
+
+        impl #impl_generics core::str::FromStr for #name #ty_generics #where_clause
+        {
+            type Err = #err;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err>
+            {
+                // Digits and radix of a decimal, 0x hex or 0b binary literal
+                #[allow(dead_code)]
+                fn literal(value: &str) -> (&str, u32)
+                {
+                    if let Some(d) = value.strip_prefix("0x").or_else(|| value.strip_prefix("0X"))
+                    {
+                        (d, 16)
+                    }
+                    else if let Some(d) = value.strip_prefix("0b").or_else(|| value.strip_prefix("0B"))
+                    {
+                        (d, 2)
+                    }
+                    else
+                    {
+                        (value, 10)
+                    }
+                }
+
+                // Value of a literal, with optional _ separators
+                #[allow(dead_code)]
+                fn parse_value(field: &'static str, value: &str) -> Result<u128, #err>
+                {
+                    let (digits, radix) = literal(value);
+                    let mut val: u128 = 0;
+                    let mut empty = true;
+
+                    for c in digits.chars().filter(|c| *c != '_')
+                    {
+                        let digit = c.to_digit(radix).ok_or(#err::InvalidValue(field))?;
+                        val = val.checked_mul(radix as u128)
+                                 .and_then(|v| v.checked_add(digit as u128))
+                                 .ok_or(#err::Overflow(field))?;
+                        empty = false;
+                    }
+
+                    if empty
+                    {
+                        return Err(#err::InvalidValue(field));
+                    }
+                    Ok(val)
+                }
+
+                // Value of a literal as bytes, least significant byte first, with optional _ separators
+                #[allow(dead_code)]
+                fn parse_bytes<const N: usize>(field: &'static str, value: &str) -> Result<[u8; N], #err>
+                {
+                    let (digits, radix) = literal(value);
+                    let mut val = [0u8; N];
+                    let mut empty = true;
+
+                    for c in digits.chars().filter(|c| *c != '_')
+                    {
+                        let mut carry = c.to_digit(radix).ok_or(#err::InvalidValue(field))?;
+                        for byte in val.iter_mut()
+                        {
+                            let x = *byte as u32 * radix + carry;
+                            *byte = x as u8;
+                            carry = x >> 8;
+                        }
+                        if carry != 0
+                        {
+                            return Err(#err::Overflow(field));
+                        }
+                        empty = false;
+                    }
+
+                    if empty
+                    {
+                        return Err(#err::InvalidValue(field));
+                    }
+                    Ok(val)
+                }
+
+                let #mutability ret = <Self as Default>::default();
+                if s.trim().is_empty()
+                {
+                    return Ok(ret);
+                }
+
+                // Offset of the current entry in the string, for the errors
+                let mut start = 0;
+                for entry in s.split(',')
+                {
+                    let trimmed = entry.trim();
+                    let at = start + entry.len() - entry.trim_start().len();
+                    let (field, value) = trimmed.split_once('=')
+                                                .ok_or_else(|| #err::Syntax(#entry::new(trimmed, at)))?;
+                    let value = value.trim();
+
+                    match field.trim_end()
+                    {
+                        #(#arms)*
+                        other => return Err(#err::UnknownField(#entry::new(other, at))),
+                    }
+
+                    start += entry.len() + 1;
+                }
+
+                Ok(ret)
             }
         }
     }
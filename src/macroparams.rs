@@ -6,19 +6,39 @@
  */
 
 use syn::parse::{Parse, ParseBuffer};
-use syn::{Ident, Token};
+use syn::{Ident, Token, Meta, Path, TypeArray, Type, Expr, Lit};
 use syn::punctuated::Punctuated;
-use proc_macro2::Span;
+use syn::token::Bracket;
+use proc_macro2::{Span, TokenStream};
+use quote::{quote, format_ident};
 
 
-#[derive(Debug)]
+static ERR_PARAMS: &str = "Wrong parameter supplied. Parameters can be: 'u8' / 'u16' / 'u32' / 'u64' / 'u128' for size of bitfield, or an array of those such as '[u32; 8]'.\n 'lsb' / 'msb' for the order of field declaration.\n 'no_pub' to specify by hand which field should be declared as public.\n 'pp' to implement the pretty print function.\n 'strict' to reject bits not covered by any field.\n 'report' to print the computed layout as a warning, and expose it as the LAYOUT_REPORT constant.\n 'derive(...)' to derive traits on the generated structure.\n 'bytes' to generate the conversions from and to big and little endian bytes.\n 'byte_order = big' / 'byte_order = little' for the canonical byte order of to_bytes / from_bytes, which implies 'bytes'.\n 'view' to generate views over byte slices, in the canonical byte order.\n 'mmio' to generate a volatile wrapper for memory-mapped registers.\n 'bus' / 'bus = path::to::Bus' to generate register accesses through a bus backend.\n 'atomic' to back the bitfield by an atomic integer, with 'ordering = relaxed' / 'ordering = acq_rel' / 'ordering = seq_cst'.\n 'cell' to back the bitfield by a Cell, for single-threaded shared accesses.\n 'update' to generate update() and set_fields(), writing several fields with a single read-modify-write.\n 'dynamic' to generate an enum of the fields, and accesses to fields chosen at runtime.\n 'assert' to generate the mismatch report used by assert_bitf_eq!.\n 'pattern' to generate the matching against patterns with don't-care bits, used by bitf_pattern! and bitf_decoder!.\n 'serde' / 'serde = fields' / 'serde = raw' to implement Serialize and Deserialize as a map of fields or as raw.";
+
 pub struct MacroParams
 {
     pub bitfield_size:  usize,
     pub endianness:     Endianness,
-    pub ty:             Ident,
+    pub ty:             Ident,                  // The type of raw, or of its elements when backed by an array
+    pub array_len:      Option<usize>,          // The number of elements of raw, when backed by an array
     pub no_pub:         bool,
     pub pprint:         bool,
+    pub strict:         bool,
+    pub report:         bool,
+    pub derives:        Vec<Path>,
+    pub bytes:          bool,                   // Generate the conversions from and to bytes
+    pub byte_order:     Option<ByteOrder>,      // The canonical byte order of the bitfield on the wire
+    pub view:           bool,
+    pub mmio:           bool,
+    pub bus:            Option<Path>,           // The backend trait of the register accesses, see bitf_bus!()
+    pub atomic:         bool,                   // raw is an AtomicUxx
+    pub ordering:       AtomicOrdering,         // The memory ordering of the accesses to an atomic raw
+    pub cell:           bool,                   // raw is a Cell<uxx>
+    pub serde:          Option<SerdeRepr>,      // The representation of the bitfield for serde
+    pub update:         bool,                   // Generate the batched field writes
+    pub dynamic:        bool,                   // Generate the enum of the fields and the accesses through it
+    pub assert:         bool,                   // Generate the mismatch report used by assert_bitf_eq!
+    pub pattern:        bool,                   // Generate the matching against patterns with don't-care bits
 }
 
 impl Default for MacroParams
@@ -30,8 +50,144 @@ impl Default for MacroParams
             bitfield_size:  0,
             endianness:     Endianness::Lsb,
             ty:             Ident::new("pub", Span::call_site()),
+            array_len:      None,
             no_pub:         false,
             pprint:         false,
+            strict:         false,
+            report:         false,
+            derives:        Vec::new(),
+            bytes:          false,
+            byte_order:     None,
+            view:           false,
+            mmio:           false,
+            bus:            None,
+            atomic:         false,
+            ordering:       AtomicOrdering::SeqCst,
+            cell:           false,
+            serde:          None,
+            update:         false,
+            dynamic:        false,
+            assert:         false,
+            pattern:        false,
+        }
+    }
+}
+
+impl MacroParams
+{
+    // Size in bits of an element of raw, which is raw itself when not backed by an array
+    pub fn elem_size(&self) -> usize
+    {
+        self.ty.to_string()[1..].parse::<usize>().unwrap()
+    }
+
+    pub fn is_array(&self) -> bool
+    {
+        self.array_len.is_some()
+    }
+
+    // True if the conversions from and to bytes are generated, either asked for or needed by a canonical byte order
+    pub fn has_bytes(&self) -> bool
+    {
+        self.bytes || self.byte_order.is_some()
+    }
+
+    // Type of the raw field, for use in quote! code generation
+    pub fn raw_tokens(&self) -> TokenStream
+    {
+        let value = self.value_tokens();
+
+        if self.atomic
+        {
+            let atomic = self.atomic_type();
+            return quote!{ core::sync::atomic::#atomic };
+        }
+
+        if self.cell
+        {
+            return quote!{ core::cell::Cell<#value> };
+        }
+
+        value
+    }
+
+    // Type of the value held by the raw field, whatever its storage
+    pub fn value_tokens(&self) -> TokenStream
+    {
+        let ty = &self.ty;
+
+        match self.array_len
+        {
+            Some(len) => quote!{ [#ty; #len] },
+            None => quote!{ #ty },
+        }
+    }
+
+    // Type of the atomic integer backing the bitfield
+    pub fn atomic_type(&self) -> Ident
+    {
+        format_ident!("AtomicU{}", self.bitfield_size)
+    }
+
+    // Value of the raw field holding `raw`, for use in quote! code generation
+    pub fn wrap_tokens(&self, raw: &TokenStream) -> TokenStream
+    {
+        if self.atomic
+        {
+            let atomic = self.atomic_type();
+            return quote!{ core::sync::atomic::#atomic::new(#raw) };
+        }
+
+        if self.cell
+        {
+            return quote!{ core::cell::Cell::new(#raw) };
+        }
+
+        raw.clone()
+    }
+
+    // True if the fields are written through a shared reference
+    pub fn is_shared(&self) -> bool
+    {
+        self.atomic || self.cell
+    }
+
+    // Receiver of the methods writing the fields, a shared reference for atomic and Cell bitfields
+    pub fn setter_receiver(&self) -> TokenStream
+    {
+        if self.is_shared() { quote!{ self: &Self } } else { quote!{ self: &mut Self } }
+    }
+
+    // Mutability of a binding holding a bitfield whose fields are written, following setter_receiver()
+    pub fn binding_mutability(&self) -> TokenStream
+    {
+        if self.is_shared() { quote!{} } else { quote!{ mut } }
+    }
+
+    // Expression reading the value of the raw field of `recv`
+    pub fn load_tokens(&self, recv: &TokenStream) -> TokenStream
+    {
+        if self.atomic
+        {
+            let order = self.ordering.load_tokens();
+            return quote!{ #recv.raw.load(#order) };
+        }
+
+        if self.cell
+        {
+            return quote!{ #recv.raw.get() };
+        }
+
+        quote!{ #recv.raw }
+    }
+
+    // Zero value of the raw field, for use in quote! code generation
+    pub fn zero_tokens(&self) -> TokenStream
+    {
+        match self.array_len
+        {
+            Some(len) => quote!{ [0; #len] },
+            None => quote!{ 0x0 },
         }
     }
 }
@@ -43,31 +199,78 @@ pub enum Endianness
     Msb,
 }
 
+#[derive(Debug, PartialEq)]
+pub enum ByteOrder
+{
+    Big,
+    Little,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AtomicOrdering
+{
+    Relaxed,
+    AcqRel,
+    SeqCst,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SerdeRepr
+{
+    Fields,         // A map of the field names to their values
+    Raw,            // The value of raw
+}
+
+impl AtomicOrdering
+{
+    // Ordering of the loads
+    pub fn load_tokens(&self) -> TokenStream
+    {
+        match self
+        {
+            AtomicOrdering::Relaxed => quote!{ core::sync::atomic::Ordering::Relaxed },
+            AtomicOrdering::AcqRel  => quote!{ core::sync::atomic::Ordering::Acquire },
+            AtomicOrdering::SeqCst  => quote!{ core::sync::atomic::Ordering::SeqCst },
+        }
+    }
+
+    // Ordering of the successful read-modify-writes
+    pub fn rmw_tokens(&self) -> TokenStream
+    {
+        match self
+        {
+            AtomicOrdering::Relaxed => quote!{ core::sync::atomic::Ordering::Relaxed },
+            AtomicOrdering::AcqRel  => quote!{ core::sync::atomic::Ordering::AcqRel },
+            AtomicOrdering::SeqCst  => quote!{ core::sync::atomic::Ordering::SeqCst },
+        }
+    }
+}
+
 impl Parse for MacroParams
 {
     fn parse(input: &ParseBuffer) -> syn::Result<Self>
     {
-        let params = Punctuated::<Ident, Token![,]>::parse_terminated(input).unwrap();
-       
         let mut ret_struct = MacroParams::default();
-        
-        for p in &params
+
+        while !input.is_empty()
         {
-            let val = p.to_string();
-            match val.as_ref()
+            // Bitfields backed by an array, such as [u32; 8]
+            if input.peek(Bracket)
             {
-                "u8" | "u16" | "u32" | "u64" | "u128"   => 
-                {
-                    let size = &val[1..];
-                    ret_struct.bitfield_size = size.parse::<usize>().unwrap(); 
-                    ret_struct.ty = p.clone();
-                },
-                "lsb"   => ret_struct.endianness = Endianness::Lsb,
-                "msb"   => ret_struct.endianness = Endianness::Msb,
-                "no_pub"=> ret_struct.no_pub = true,
-                "pp"    => ret_struct.pprint = true,
-                _ => { return Err(syn::Error::new(p.span(), "Wrong parameter supplied. Parameters can be: 'u8' / 'u16' / 'u32' / 'u64' / 'u128' for size of bitfield.\n 'lsb' / 'msb' for the order of field declaration.\n 'no_pub' to specify by hand which field should be declared as public.")) }
+                let array = input.parse::<TypeArray>()?;
+                ret_struct.parse_array(&array)?;
+            }
+            else
+            {
+                let param = input.parse::<Meta>()?;
+                ret_struct.parse_meta(&param)?;
             }
+
+            if input.is_empty()
+            {
+                break;
+            }
+            input.parse::<Token![,]>()?;
         }
 
         if ret_struct.bitfield_size == 0
@@ -75,7 +278,172 @@ impl Parse for MacroParams
             panic!("Error: no size specified. Please specify a size for the bitfield, with one of the following parameter: 'u8' / 'u16' / 'u32' / 'u64' / 'u128'");
         }
 
+        if ret_struct.atomic && (ret_struct.is_array() || ret_struct.bitfield_size > 64 || ret_struct.mmio || ret_struct.bus.is_some())
+        {
+            return Err(syn::Error::new(input.span(), "The 'atomic' parameter requires a bitfield backed by 'u8' / 'u16' / 'u32' / 'u64', and cannot be combined with 'mmio' or 'bus'"));
+        }
+
+        if ret_struct.cell && (ret_struct.is_array() || ret_struct.atomic || ret_struct.mmio || ret_struct.bus.is_some())
+        {
+            return Err(syn::Error::new(input.span(), "The 'cell' parameter requires a bitfield backed by a primitive: 'u8' / 'u16' / 'u32' / 'u64' / 'u128', and cannot be combined with 'atomic', 'mmio' or 'bus'"));
+        }
+
+        if ret_struct.mmio && ret_struct.is_array()
+        {
+            return Err(syn::Error::new(input.span(), "The 'mmio' parameter requires a bitfield backed by a primitive: 'u8' / 'u16' / 'u32' / 'u64' / 'u128'"));
+        }
+
+        if ret_struct.update && ret_struct.is_array()
+        {
+            return Err(syn::Error::new(input.span(), "The 'update' parameter requires a bitfield backed by a primitive: 'u8' / 'u16' / 'u32' / 'u64' / 'u128'"));
+        }
+
+        if ret_struct.pattern && ret_struct.is_array()
+        {
+            return Err(syn::Error::new(input.span(), "The 'pattern' parameter requires a bitfield backed by a primitive: 'u8' / 'u16' / 'u32' / 'u64' / 'u128'"));
+        }
+
+        if ret_struct.view && ret_struct.byte_order.is_none()
+        {
+            return Err(syn::Error::new(input.span(), "The 'view' parameter requires a canonical byte order: 'byte_order = big' or 'byte_order = little'"));
+        }
+
         Ok( ret_struct )
     }
 }
 
+impl MacroParams
+{
+    fn parse_array(&mut self, array: &TypeArray) -> syn::Result<()>
+    {
+        let err = || syn::Error::new_spanned(array, "Expected an array of 'u8' / 'u16' / 'u32' / 'u64' / 'u128' with a literal length, such as '[u32; 8]'");
+
+        let elem = match array.elem.as_ref()
+        {
+            Type::Path(p) => p.path.require_ident().map_err(|_| err())?,
+            _ => return Err(err()),
+        };
+
+        let len = match &array.len
+        {
+            Expr::Lit(l) => match &l.lit
+            {
+                Lit::Int(i) => i.base10_parse::<usize>()?,
+                _ => return Err(err()),
+            },
+            _ => return Err(err()),
+        };
+
+        match elem.to_string().as_ref()
+        {
+            "u8" | "u16" | "u32" | "u64" | "u128" if len > 0 =>
+            {
+                self.ty = elem.clone();
+                self.array_len = Some(len);
+                self.bitfield_size = self.elem_size() * len;
+                Ok(())
+            },
+            _ => Err(err()),
+        }
+    }
+
+    fn parse_meta(&mut self, param: &Meta) -> syn::Result<()>
+    {
+        let p = match param
+        {
+            // Traits to derive on the generated structure, whatever the order of the attributes
+            Meta::List(list) if list.path.is_ident("derive") =>
+            {
+                let paths = list.parse_args_with(Punctuated::<Path, Token![,]>::parse_terminated)?;
+                self.derives.extend(paths);
+                return Ok(());
+            },
+            // Canonical byte order of the bitfield
+            Meta::NameValue(nv) if nv.path.is_ident("byte_order") =>
+            {
+                let order = match &nv.value
+                {
+                    Expr::Path(p) if p.path.is_ident("big") => ByteOrder::Big,
+                    Expr::Path(p) if p.path.is_ident("little") => ByteOrder::Little,
+                    _ => return Err(syn::Error::new_spanned(&nv.value, "Expected 'big' or 'little' as byte order")),
+                };
+                self.byte_order = Some(order);
+                return Ok(());
+            },
+            // Memory ordering of the accesses to an atomic raw
+            Meta::NameValue(nv) if nv.path.is_ident("ordering") =>
+            {
+                self.ordering = match &nv.value
+                {
+                    Expr::Path(p) if p.path.is_ident("relaxed") => AtomicOrdering::Relaxed,
+                    Expr::Path(p) if p.path.is_ident("acq_rel") => AtomicOrdering::AcqRel,
+                    Expr::Path(p) if p.path.is_ident("seq_cst") => AtomicOrdering::SeqCst,
+                    _ => return Err(syn::Error::new_spanned(&nv.value, "Expected 'relaxed', 'acq_rel' or 'seq_cst' as ordering")),
+                };
+                return Ok(());
+            },
+            // Representation of the bitfield for serde, a map of fields when not given
+            Meta::NameValue(nv) if nv.path.is_ident("serde") =>
+            {
+                self.serde = match &nv.value
+                {
+                    Expr::Path(p) if p.path.is_ident("fields") => Some(SerdeRepr::Fields),
+                    Expr::Path(p) if p.path.is_ident("raw") => Some(SerdeRepr::Raw),
+                    _ => return Err(syn::Error::new_spanned(&nv.value, "Expected 'fields' or 'raw' as serde representation")),
+                };
+                return Ok(());
+            },
+            Meta::Path(path) if path.is_ident("serde") =>
+            {
+                self.serde = Some(SerdeRepr::Fields);
+                return Ok(());
+            },
+            // Backend trait of the register accesses, Bus when no path is given
+            Meta::NameValue(nv) if nv.path.is_ident("bus") =>
+            {
+                match &nv.value
+                {
+                    Expr::Path(p) => self.bus = Some(p.path.clone()),
+                    _ => return Err(syn::Error::new_spanned(&nv.value, "Expected the path of the bus trait")),
+                }
+                return Ok(());
+            },
+            Meta::Path(path) if path.is_ident("bus") =>
+            {
+                self.bus = Some(syn::parse_quote!{ Bus });
+                return Ok(());
+            },
+            Meta::Path(path) => path.require_ident()?,
+            _ => return Err(syn::Error::new_spanned(param, ERR_PARAMS)),
+        };
+
+        let val = p.to_string();
+        match val.as_ref()
+        {
+            "u8" | "u16" | "u32" | "u64" | "u128"   => 
+            {
+                let size = &val[1..];
+                self.bitfield_size = size.parse::<usize>().unwrap(); 
+                self.ty = p.clone();
+            },
+            "lsb"   => self.endianness = Endianness::Lsb,
+            "msb"   => self.endianness = Endianness::Msb,
+            "no_pub"=> self.no_pub = true,
+            "pp"    => self.pprint = true,
+            "strict"=> self.strict = true,
+            "report"=> self.report = true,
+            "bytes" => self.bytes = true,
+            "view"  => self.view = true,
+            "mmio"  => self.mmio = true,
+            "atomic"=> self.atomic = true,
+            "cell"  => self.cell = true,
+            "update"=> self.update = true,
+            "dynamic" => self.dynamic = true,
+            "assert"=> self.assert = true,
+            "pattern" => self.pattern = true,
+            _ => { return Err(syn::Error::new(p.span(), ERR_PARAMS)) }
+        }
+
+        Ok(())
+    }
+}
@@ -4,6 +4,9 @@
  *
  */
 
+// The tests set raw on default values, as users of the macro do
+#![allow(clippy::field_reassign_with_default)]
+
 use bitf::*;
 
 #[repr(C)]
@@ -144,3 +147,1334 @@ fn pretty_print()
 
     long.pprint();
 }
+
+// The layout report is raised as a warning
+#[bitf(u16, msb, strict, report)]
+#[allow(deprecated)]
+struct StrictBitf
+{
+    /// Operation to execute
+    opcode_6:       (),
+    _reserved_2:    (),
+    /// Immediate operand of the operation
+    operand_8:      (),
+}
+
+#[test]
+fn strict_layout()
+{
+    let mut sbitf = StrictBitf::default();
+
+    sbitf.set_opcode(0b101101);
+    sbitf.set_operand(0xa5);
+
+    // Should have 1011 0100 1010 0101
+    assert_eq!(sbitf.raw, 0xb4a5);
+    assert_eq!(sbitf.opcode(), 0b101101);
+}
+
+#[test]
+fn layout_report()
+{
+    let expected = ["bitf layout of `StrictBitf` (u16, msb)",
+                    "  bits       | width | field",
+                    "  10..=15    | 6     | opcode",
+                    "  8..=9      | 2     | <reserved>",
+                    "  0..=7      | 8     | operand",
+                    ""];
+    assert_eq!(StrictBitf::LAYOUT_REPORT, expected.join("\n"));
+}
+
+// Unused bits raise a warning, which the lint attributes of the structure silence
+#[allow(deprecated)]
+#[bitf(u16)]
+struct SpareBitf
+{
+    low_4:          (),
+    high_4:         (),
+}
+
+#[bitf(u16)]
+#[expect(deprecated, reason = "the upper bits are spare")]
+struct ExpectSpareBitf
+{
+    low_4:          (),
+    high_4:         (),
+}
+
+#[test]
+fn unused_bits()
+{
+    let mut spare = SpareBitf::default();
+    spare.set_high(0xf);
+    assert_eq!(spare.raw, 0xf0);
+
+    let mut spare = ExpectSpareBitf::default();
+    spare.set_low(0x3);
+    assert_eq!(spare.raw, 0x3);
+}
+
+// clear_<field>() is only generated for w1c fields, so the name is free otherwise
+#[bitf(u8)]
+struct ClearNameBitf
+{
+    state_4:        (),
+    clear_state_1:  (),
+    _reserved_3:    (),
+}
+
+#[test]
+fn clear_prefix()
+{
+    let mut bitf = ClearNameBitf::default();
+    bitf.set_clear_state(1);
+    bitf.set_state(0x5);
+    assert_eq!(bitf.raw, 0x15);
+}
+
+#[bitf(u8, pp)]
+struct RevBitf
+{
+    mode_2:         (),
+    #[cfg(test)]
+    extra_3:        (),
+    #[cfg(not(test))]
+    legacy_1:       (),
+    status_3:       (),
+}
+
+#[test]
+fn conditional_fields()
+{
+    let mut rbitf = RevBitf::default();
+
+    rbitf.set_mode(2);
+    rbitf.set_extra(5);
+    rbitf.set_status(3);
+
+    // Should have 011 101 10
+    assert_eq!(rbitf.raw, 0b0111_0110);
+    assert_eq!(rbitf.extra(), 5);
+    assert_eq!(rbitf.status(), 3);
+
+    rbitf.pprint();
+}
+
+// Fields never enabled together, which would not fit in the bitfield at once
+#[bitf(u8, msb, pp)]
+struct RevMsbBitf
+{
+    mode_2:         (),
+    #[cfg(test)]
+    extra_6:        (),
+    #[cfg(not(test))]
+    legacy_6:       (),
+}
+
+#[bitf(u8, msb, strict)]
+struct SwapMsbBitf
+{
+    #[cfg(test)]
+    current_8:      (),
+    #[cfg(not(test))]
+    legacy_8:       (),
+}
+
+#[test]
+fn conditional_fields_msb()
+{
+    let mut rbitf = RevMsbBitf::default();
+
+    rbitf.set_mode(2);
+    rbitf.set_extra(0b101101);
+
+    // Should have 10 101101
+    assert_eq!(rbitf.raw, 0b1010_1101);
+    assert_eq!(rbitf.mode(), 2);
+    assert_eq!(rbitf.extra(), 0b101101);
+
+    let mut swap = SwapMsbBitf::default();
+    swap.set_current(0xa5);
+    assert_eq!(swap.raw, 0xa5);
+
+    rbitf.pprint();
+}
+
+trait Mode {}
+struct Locked;
+struct Unlocked;
+impl Mode for Locked {}
+impl Mode for Unlocked {}
+
+#[bitf(u8)]
+struct Ctrl<M>
+    where M: Mode
+{
+    enable_1:   (),
+    speed_7:    (),
+}
+
+impl Ctrl<Locked>
+{
+    fn unlock(self) -> Ctrl<Unlocked>
+    {
+        Ctrl { raw: self.raw, _marker: core::marker::PhantomData }
+    }
+}
+
+#[test]
+fn generic_bitf()
+{
+    let mut locked = Ctrl::<Locked>::default();
+    locked.set_speed(100);
+
+    let mut unlocked = locked.unlock();
+    unlocked.set_enable(1);
+
+    assert_eq!(unlocked.speed(), 100);
+    assert_eq!(unlocked.raw, 0xc9);
+}
+
+#[bitf(u8, derive(Debug, PartialEq, Eq, Clone, Copy))]
+#[derive(Hash)]
+struct DerivedBitf
+{
+    flag_1:         (),
+    _reserved_3:    (),
+    value_4:        (),
+}
+
+#[test]
+fn derived_bitf()
+{
+    let a = DerivedBitf { raw: 0b1010_0001 };
+    let b = DerivedBitf { raw: 0b1010_1111 };
+
+    // Reserved bits are not compared
+    assert_eq!(a, b);
+    assert_eq!(format!("{:?}", a), "DerivedBitf { flag: 1, value: 10 }");
+
+    let c = a;
+    assert_eq!(c, DerivedBitf { raw: 0b1010_0001 });
+
+    let mut set = std::collections::HashSet::new();
+    set.insert(a);
+    assert!(set.contains(&b));
+}
+
+// Derives of the same name from another path are not implemented by the macro
+mod other_derives
+{
+    pub use core::fmt::Debug;
+}
+
+#[bitf(u8, derive(core::fmt::Debug, std::cmp::PartialEq))]
+struct StdPathBitf
+{
+    flag_1:         (),
+    _reserved_7:    (),
+}
+
+#[bitf(u8)]
+#[derive(other_derives::Debug)]
+struct ForeignDeriveBitf
+{
+    flag_1:         (),
+    _reserved_7:    (),
+}
+
+#[test]
+fn derive_paths()
+{
+    assert_eq!(StdPathBitf { raw: 0x81 }, StdPathBitf { raw: 0x01 });
+    assert_eq!(format!("{:?}", StdPathBitf { raw: 0x81 }), "StdPathBitf { flag: 1 }");
+
+    assert_eq!(format!("{:?}", ForeignDeriveBitf { raw: 0x81 }), "ForeignDeriveBitf { raw: 129 }");
+}
+
+#[bitf([u32; 5], pp, derive(Debug, PartialEq))]
+struct RadioFrame
+{
+    preamble_24:    (),
+    address_40:     (),
+    length_8:       (),
+    payload_64:     (),
+    crc_24:         (),
+}
+
+#[bitf([u64; 4])]
+struct WideBitf
+{
+    flag_1:         (),
+    key_200:        (),
+    tail_55:        (),
+}
+
+#[test]
+fn array_bitf()
+{
+    let mut frame = RadioFrame::default();
+
+    frame.set_preamble(0xaaaaaa);
+    frame.set_address(0x12_3456_789a);
+    frame.set_length(0x55);
+    frame.set_payload(0xdead_beef_cafe_f00d);
+    frame.set_crc(0xabcdef);
+
+    assert_eq!(frame.preamble(), 0xaaaaaa);
+    assert_eq!(frame.address(), 0x12_3456_789a);
+    assert_eq!(frame.length(), 0x55);
+    assert_eq!(frame.payload(), 0xdead_beef_cafe_f00d);
+    assert_eq!(frame.crc(), 0xabcdef);
+    // address straddles the first and second elements
+    assert_eq!(frame.raw[0], 0x9aaa_aaaa);
+    assert_eq!(frame.raw[1], 0x1234_5678);
+    assert_eq!(frame.raw[2] & 0xff, 0x55);
+
+    frame.pprint();
+
+    let mut wide = WideBitf::default();
+    let mut key = [0u8; 25];
+    key.iter_mut().enumerate().for_each(|(i, b)| *b = i as u8 + 1);
+
+    wide.set_flag(1);
+    wide.set_key(key);
+    wide.set_tail(0x7f_ffff_ffff_ffff);
+
+    assert_eq!(wide.key(), key);
+    assert_eq!(wide.flag(), 1);
+    assert_eq!(wide.tail(), 0x7f_ffff_ffff_ffff);
+    assert_eq!(wide.raw[0] & 0x1ff, 0x003);
+}
+
+#[bitf([u32; 5], bytes, derive(Debug, PartialEq))]
+struct RadioBytes
+{
+    preamble_24:    (),
+    body_112:       (),
+    crc_24:         (),
+}
+
+#[bitf(u32, msb, byte_order = big)]
+struct Ipv4Word
+{
+    version_4:      (),
+    ihl_4:          (),
+    tos_8:          (),
+    length_16:      (),
+}
+
+#[test]
+fn byte_order()
+{
+    let buf = [0x45, 0x00, 0x00, 0x54, 0xde, 0xad];
+    let word = Ipv4Word::from_slice(&buf).unwrap();
+
+    assert_eq!(word.version(), 4);
+    assert_eq!(word.ihl(), 5);
+    assert_eq!(word.length(), 0x54);
+    assert_eq!(word.to_bytes(), [0x45, 0x00, 0x00, 0x54]);
+    assert_eq!(word.to_le_bytes(), [0x54, 0x00, 0x00, 0x45]);
+    assert!(Ipv4Word::from_slice(&buf[..3]).is_none());
+
+    let mut frame = RadioBytes::default();
+    frame.set_preamble(0xaabbcc);
+    frame.set_crc(0x112233);
+
+    let be = frame.to_be_bytes();
+    assert_eq!(&be[..3], &[0x11, 0x22, 0x33]);
+    assert_eq!(&be[17..], &[0xaa, 0xbb, 0xcc]);
+    assert_eq!(RadioBytes::from_be_bytes(be), frame);
+    assert_eq!(RadioBytes::from_le_bytes(frame.to_le_bytes()), frame);
+}
+
+#[bitf(u32, msb, byte_order = big, view)]
+struct Ipv4Head
+{
+    version_4:      (),
+    ihl_4:          (),
+    tos_8:          (),
+    length_16:      (),
+}
+
+#[bitf(u16, byte_order = little, view)]
+struct LeWord
+{
+    lo_4:           (),
+    mid_8:          (),
+    hi_4:           (),
+}
+
+#[test]
+fn byte_views()
+{
+    let mut buf = [0x45, 0x00, 0x00, 0x54, 0xde, 0xad];
+
+    let view = Ipv4HeadRef::new(&buf).unwrap();
+    assert_eq!(view.version(), 4);
+    assert_eq!(view.ihl(), 5);
+    assert_eq!(view.length(), 0x54);
+
+    let mut view = Ipv4HeadMut::new(&mut buf[..4]).unwrap();
+    view.set_tos(0xb8);
+    view.set_length(0x1234);
+    assert_eq!(view.tos(), 0xb8);
+    assert_eq!(buf, [0x45, 0xb8, 0x12, 0x34, 0xde, 0xad]);
+
+    assert!(Ipv4HeadRef::new(&buf[..3]).is_none());
+    assert_eq!(Ipv4HeadRef::SIZE, 4);
+
+    let le = [0x21, 0x43];
+    let view = LeWordRef::new(&le).unwrap();
+    assert_eq!(view.lo(), 0x1);
+    assert_eq!(view.mid(), 0x32);
+    assert_eq!(view.hi(), 0x4);
+    assert_eq!(LeWord::from_bytes(le).mid(), view.mid());
+}
+
+#[bitf(u32, mmio, derive(Clone, Copy))]
+struct UartCtrl
+{
+    enable_1:       (),
+    parity_2:       (),
+    _reserved_5:    (),
+    baud_div_16:    (),
+    _reserved_8:    (),
+}
+
+#[test]
+fn mmio_register()
+{
+    // Heap allocated stand-in for the peripheral memory
+    let mut mem = Box::new(0x0000_0100u32);
+    let reg = unsafe { UartCtrlReg::from_ptr(&mut *mem) };
+
+    assert_eq!(reg.read().baud_div(), 1);
+
+    reg.modify(|r, w|
+    {
+        w.set_baud_div(r.baud_div() * 0x20);
+        w.set_enable(1);
+    });
+
+    let mut value = reg.read();
+    assert_eq!(value.raw, 0x0000_2001);
+
+    value.set_parity(2);
+    reg.write(value);
+
+    assert_eq!(reg.read().parity(), 2);
+    assert_eq!(reg.as_ptr() as *const u32, &*mem as *const u32);
+    assert_eq!(*mem, 0x0000_2005);
+}
+
+#[bitf(u8, mmio)]
+struct UartStatus
+{
+    rx_ready_1:     (),
+    tx_empty_1:     (),
+    _reserved_6:    (),
+}
+
+#[bitf_block]
+struct Uart
+{
+    #[offset(0x00)]
+    ctrl:   UartCtrl,
+    /// Status flags of the UART
+    #[offset(0x04)]
+    status: UartStatus,
+    #[offset(0x0c)]
+    scratch: UartCtrl,
+}
+
+#[test]
+fn register_block()
+{
+    let mut mem = Box::new([0u32; 4]);
+    mem[1] = 0b10;
+
+    let uart = unsafe { Uart::from_ptr(mem.as_mut_ptr() as *mut u8) };
+
+    assert_eq!(core::mem::size_of::<Uart>(), 0x10);
+    assert_eq!(uart.status().read().tx_empty(), 1);
+
+    uart.ctrl().modify(|_, w| w.set_enable(1));
+    uart.scratch().write(UartCtrl { raw: 0xdead_beef });
+
+    assert_eq!(mem[0], 1);
+    assert_eq!(mem[3], 0xdead_beef);
+}
+
+mod hw
+{
+    bitf::bitf_bus!();
+}
+
+use hw::{MockBus, BusAccess};
+
+#[bitf(u16, bus = hw::Bus)]
+struct SpiCtrl
+{
+    start_1:        (),
+    busy_1:         (),
+    len_6:          (),
+    _reserved_8:    (),
+}
+
+const SPI_CTRL: u64 = 0x4000_1000;
+
+// Driver logic under test
+fn spi_transfer<B: hw::Bus>(bus: &mut B, len: u16)
+{
+    while SpiCtrl::read_from(bus, SPI_CTRL).busy() == 1 {}
+    SpiCtrl::modify_on(bus, SPI_CTRL, |_, w| { w.set_len(len); w.set_start(1); });
+}
+
+#[test]
+fn bus_backend()
+{
+    let mut bus = MockBus::new();
+    bus.script_read(SPI_CTRL, 0b10);
+    bus.script_read(SPI_CTRL, 0b10);
+
+    spi_transfer(&mut bus, 12);
+
+    assert_eq!(bus.accesses().len(), 5);
+    assert_eq!(bus.accesses()[0], BusAccess::Read { addr: SPI_CTRL, bits: 16, value: 0b10 });
+    assert_eq!(bus.writes(), vec![(SPI_CTRL, (12 << 2) | 1)]);
+    assert_eq!(SpiCtrl::read_from(&mut bus, SPI_CTRL).len(), 12);
+}
+
+#[bitf(u32, mmio)]
+struct IrqStatus
+{
+    #[access(w1c)]
+    rx_done_1:      (),
+    #[access(w1c)]
+    tx_done_1:      (),
+    #[access(ro)]
+    level_6:        (),
+    #[access(wo)]
+    trigger_8:      (),
+    #[access(w1s)]
+    force_8:        (),
+    mask_8:         (),
+}
+
+#[test]
+fn access_policies()
+{
+    // Both interrupts pending, garbage in the write-only and write-1-to-set fields
+    let mut mem = Box::new(0x00ff_ff3fu32);
+    let reg = unsafe { IrqStatusReg::from_ptr(&mut *mem) };
+
+    assert_eq!(reg.read().rx_done(), 1);
+    assert_eq!(reg.read().level(), 0xf);
+
+    // Clearing rx_done must not clear tx_done
+    reg.modify(|_, w| w.clear_rx_done());
+    assert_eq!(*mem, 0x0000_0001 | (0xf << 2));
+
+    let mut mem = Box::new(0u32);
+    let reg = unsafe { IrqStatusReg::from_ptr(&mut *mem) };
+    reg.modify(|_, w| { w.set_trigger(0xa5); w.set_mask(0x0f); });
+    assert_eq!(*mem, 0x0f00_a500);
+}
+
+#[bitf(u16, atomic, ordering = relaxed, derive(Debug))]
+struct SharedState
+{
+    ready_1:        (),
+    #[access(w1c)]
+    irq_1:          (),
+    count_14:       (),
+}
+
+#[test]
+fn atomic_bitfield()
+{
+    let state = std::sync::Arc::new(SharedState::default());
+
+    let workers: Vec<_> = (0..4).map(|_|
+    {
+        let state = state.clone();
+        std::thread::spawn(move ||
+        {
+            for _ in 0..1000
+            {
+                let count = state.count();
+                state.set_count(count.wrapping_add(1));
+                state.set_ready(1);
+            }
+        })
+    }).collect();
+
+    for w in workers
+    {
+        w.join().unwrap();
+    }
+
+    // Concurrent updates of count never corrupt the neighbouring fields
+    assert_eq!(state.ready(), 1);
+    assert_eq!(state.irq(), 0);
+
+    state.clear_irq();
+    let count = state.count();
+    assert_eq!(state.fetch_set_count(5), count);
+    assert_eq!(state.count(), 5);
+    assert_eq!(state.irq(), 1);
+    assert_eq!(format!("{:?}", state), "SharedState { ready: 1, irq: 1, count: 5 }");
+}
+
+#[bitf(u128, cell, pp, derive(Clone, Debug, PartialEq))]
+struct MachineState
+{
+    pc_64:          (),
+    halted_1:       (),
+    _reserved_31:   (),
+    cycles_32:      (),
+}
+
+struct Cpu<'a>
+{
+    state: &'a MachineState,
+}
+
+impl Cpu<'_>
+{
+    fn step(&self)
+    {
+        self.state.set_pc(self.state.pc() + 4);
+        self.state.set_cycles(self.state.cycles() + 1);
+    }
+}
+
+#[test]
+fn cell_bitfield()
+{
+    let state = MachineState::default();
+    let (a, b) = (Cpu { state: &state }, Cpu { state: &state });
+
+    a.step();
+    b.step();
+    state.set_halted(1);
+
+    assert_eq!(state.pc(), 8);
+    assert_eq!(state.cycles(), 2);
+    assert_eq!(state.raw.get(), (2 << 96) | (1 << 64) | 8);
+
+    let copy = state.clone();
+    assert_eq!(copy, state);
+    copy.set_halted(0);
+    assert_ne!(copy, state);
+    state.pprint();
+}
+
+#[bitf(u32, mmio, update)]
+struct DmaCtrl
+{
+    enable_1:       (),
+    #[access(w1c)]
+    done_1:         (),
+    #[access(ro)]
+    busy_1:         (),
+    _reserved_5:    (),
+    burst_8:        (),
+    channel_16:     (),
+}
+
+#[test]
+fn batched_update()
+{
+    let mut ctrl = DmaCtrl { raw: 0x0000_00ff };
+
+    ctrl.update(|w| { w.set_burst(0x10).set_channel(3); });
+    assert_eq!(ctrl.raw, 0x0003_10ff);
+
+    // Fields written twice keep the last value
+    ctrl.update(|w| { w.set_enable(0).set_enable(1).set_burst(0xff).set_burst(0x20); });
+    assert_eq!(ctrl.raw, 0x0003_20ff);
+
+    ctrl.set_fields(0, 0x40, 0xffff);
+    assert_eq!(ctrl.raw, 0xffff_40fe);
+
+    // The mmio wrapper does a single read and write, and does not write back the pending done bit
+    let mut mem = Box::new(0b110u32);
+    let reg = unsafe { DmaCtrlReg::from_ptr(&mut *mem) };
+    reg.update(|w| { w.set_enable(1).set_channel(7); });
+    assert_eq!(*mem, 0x0007_0005);
+    reg.update(|w| { w.clear_done(); });
+    assert_eq!(*mem, 0x0007_0007);
+}
+
+#[bitf(u16, serde, derive(Debug, PartialEq))]
+struct PortConfig
+{
+    enable_1:       (),
+    mode_3:         (),
+    _reserved_4:    (),
+    speed_8:        (),
+}
+
+#[bitf(u32, serde = raw, atomic)]
+struct Snapshot
+{
+    lo_16:          (),
+    hi_16:          (),
+}
+
+#[bitf([u64; 4], serde)]
+struct WideKey
+{
+    flag_1:         (),
+    key_200:        (),
+    tail_55:        (),
+}
+
+#[test]
+fn serde_support()
+{
+    let mut port = PortConfig::default();
+    port.set_enable(1);
+    port.set_mode(5);
+    port.set_speed(200);
+
+    // Reserved fields are skipped
+    let json = serde_json::to_string(&port).unwrap();
+    assert_eq!(json, r#"{"enable":1,"mode":5,"speed":200}"#);
+    assert_eq!(serde_json::from_str::<PortConfig>(&json).unwrap(), port);
+
+    let err = |s: &str| serde_json::from_str::<PortConfig>(s).unwrap_err().to_string();
+    assert!(err(r#"{"enable":1,"mode":8,"speed":200}"#).contains("overflows its 3 bits"));
+    assert!(err(r#"{"enable":1,"mode":5,"speed":200,"turbo":1}"#).contains("unknown field `turbo`"));
+    assert!(err(r#"{"enable":1,"mode":5,"_reserved_4":0,"speed":200}"#).contains("unknown field `_reserved_4`"));
+    assert!(err(r#"{"enable":1,"speed":200}"#).contains("missing field `mode`"));
+    assert!(err(r#"{"enable":1,"mode":5,"mode":5,"speed":200}"#).contains("duplicate field `mode`"));
+
+    let snap = Snapshot::default();
+    snap.set_hi(0xbeef);
+    assert_eq!(serde_json::to_string(&snap).unwrap(), "3203334144");
+    assert_eq!(serde_json::from_str::<Snapshot>("3203334144").unwrap().hi(), 0xbeef);
+
+    let mut wide = WideKey::default();
+    let mut key = [0u8; 25];
+    key[0] = 0xaa;
+    key[24] = 0x80;
+    wide.set_key(key);
+    wide.set_tail(7);
+    let back: WideKey = serde_json::from_str(&serde_json::to_string(&wide).unwrap()).unwrap();
+    assert_eq!(back.key(), key);
+    assert_eq!(back.tail(), 7);
+    assert_eq!(back.raw, wide.raw);
+}
+
+#[bitf([u64; 8], serde)]
+struct NvmeEntry
+{
+    opcode_8:       (),
+    payload_504:    (),
+}
+
+#[test]
+fn serde_wide_fields()
+{
+    let mut entry = NvmeEntry::default();
+    let mut payload = [0u8; 63];
+    payload[0] = 0x01;
+    payload[62] = 0xfe;
+    entry.set_opcode(0x42);
+    entry.set_payload(payload);
+
+    // Fields wider than 128 bits are sequences of bytes
+    let json = serde_json::to_string(&entry).unwrap();
+    assert!(json.starts_with(r#"{"opcode":66,"payload":[1,0,"#));
+    let back: NvmeEntry = serde_json::from_str(&json).unwrap();
+    assert_eq!(back.payload(), payload);
+    assert_eq!(back.raw, entry.raw);
+
+    let err = serde_json::from_str::<NvmeEntry>(r#"{"opcode":66,"payload":[1,2,3]}"#).err().unwrap().to_string();
+    assert!(err.contains("invalid length 3, expected 63 bytes"));
+}
+
+#[bitf(u32, derive(Debug, Display, FromStr, PartialEq))]
+struct TimerConfig
+{
+    enable_1:       (),
+    mode_3:         (),
+    _reserved_4:    (),
+    prescaler_24:   (),
+}
+
+#[bitf([u32; 5], derive(Display, FromStr))]
+struct WideConfig
+{
+    flag_1:         (),
+    key_130:        (),
+    _reserved_29:   (),
+}
+
+#[test]
+fn text_format()
+{
+    let timer: TimerConfig = "enable=1, mode=0b11,prescaler=0x1f".parse().unwrap();
+    assert_eq!(timer.enable(), 1);
+    assert_eq!(timer.mode(), 3);
+    assert_eq!(timer.prescaler(), 0x1f);
+    assert_eq!(timer.to_string(), "enable=1,mode=3,prescaler=31");
+    assert_eq!(timer.to_string().parse::<TimerConfig>().unwrap(), timer);
+
+    // Unspecified fields are left at zero
+    assert_eq!("mode=2".parse::<TimerConfig>().unwrap().raw, 0b100);
+    assert_eq!("".parse::<TimerConfig>().unwrap().raw, 0);
+    assert_eq!("prescaler=1_000".parse::<TimerConfig>().unwrap().prescaler(), 1000);
+
+    let err = |s: &str| s.parse::<TimerConfig>().unwrap_err();
+    assert_eq!(err("mode=8"), TimerConfigParseError::Overflow("mode"));
+    assert_eq!(err("mode=0xg"), TimerConfigParseError::InvalidValue("mode"));
+    assert_eq!(err("mode=0x"), TimerConfigParseError::InvalidValue("mode"));
+
+    // The errors name the entry in error, and give its range in the parsed string
+    assert_eq!(err("turbo=1").to_string(), "unknown field `turbo` at bytes 0..5");
+    match err("mode=1, _reserved_4=0")
+    {
+        TimerConfigParseError::UnknownField(field) =>
+        {
+            assert_eq!(field.as_str(), "_reserved_4");
+            assert_eq!(field.range(), 8..19);
+        },
+        other => panic!("unexpected error {:?}", other),
+    }
+    assert_eq!(err("enable=1,mode").to_string(), "expected `field=value`, found `mode` at bytes 9..13");
+    let long = "a_field_name_much_longer_than_32_bytes=1";
+    assert_eq!(err(long).to_string(), "unknown field `a_field_name_much_longer_than_32...` at bytes 0..38");
+    assert_eq!(err("prescaler=0x1_0000_0000_0000_0000_0000_0000_0000_0000").to_string(), "value of field `prescaler` does not fit in its width");
+
+    let wide: WideConfig = "flag=1,key=0x3_0000_0000_0000_0000_0000_0000_0000_00ab".parse().unwrap();
+    assert_eq!(wide.key()[0], 0xab);
+    assert_eq!(wide.key()[16], 0x03);
+    assert_eq!(wide.to_string(), "flag=1,key=0x03000000000000000000000000000000ab");
+    assert!("key=0x4_0000_0000_0000_0000_0000_0000_0000_0000".parse::<WideConfig>().is_err());
+
+    // Decimal and binary literals of wide fields
+    let wide: WideConfig = "key=680564733841876926926749214863536422912".parse().unwrap();
+    assert_eq!(wide.key()[16], 0x02);
+    let wide: WideConfig = "key=0b11_0000_0001".parse().unwrap();
+    assert_eq!(&wide.key()[..2], &[0x01, 0x03]);
+    assert!("key=0b2".parse::<WideConfig>().is_err());
+}
+
+#[bitf(u16, dynamic)]
+struct DebugReg
+{
+    /// Enables the port
+    enable_1:       (),
+    #[access(ro)]
+    link_up_1:      (),
+    rx_mode_6:      (),
+    #[cfg(test)]
+    full_8:         (),
+    #[cfg(not(test))]
+    _reserved_8:    (),
+}
+
+#[bitf(u8, dynamic)]
+struct FullReg
+{
+    value_8:        (),
+}
+
+#[bitf([u64; 4], dynamic)]
+struct WideReg
+{
+    small_4:        (),
+    big_100:        (),
+    huge_150:       (),
+    _reserved_2:    (),
+}
+
+#[test]
+fn dynamic_fields()
+{
+    let mut reg = DebugReg::default();
+
+    let field = DebugReg::field_by_name("rx_mode").unwrap();
+    assert_eq!(field, DebugRegField::RxMode);
+    assert_eq!(field.name(), "rx_mode");
+    assert!(DebugReg::field_by_name("rxMode").is_none());
+    assert!(DebugReg::field_by_name("_reserved_8").is_none());
+
+    assert!(reg.set(field, 0x2a));
+    assert!(reg.set(DebugRegField::Full, 0xff));
+    assert_eq!(reg.rx_mode(), 0x2a);
+    assert_eq!(reg.get(DebugRegField::Full), 0xff);
+
+    // Values too wide and read-only fields are rejected
+    assert!(!reg.set(field, 0x40));
+    assert!(!reg.set(DebugRegField::LinkUp, 1));
+    assert_eq!(reg.raw, 0xffa8);
+
+    let mut full = FullReg::default();
+    assert!(full.set(FullRegField::Value, 0xff));
+    assert_eq!(full.get(FullRegField::Value), 0xff);
+
+    let mut wide = WideReg::default();
+    assert!(wide.set(WideRegField::Big, 1 << 99));
+    assert!(!wide.set(WideRegField::Big, 1 << 100));
+    assert_eq!(wide.get(WideRegField::Big), 1 << 99);
+    assert!(WideReg::field_by_name("huge").is_none());
+}
+
+#[bitf(u16, msb, dynamic)]
+struct MsbDynReg
+{
+    opcode_6:       (),
+    _reserved_2:    (),
+    operand_8:      (),
+}
+
+#[test]
+fn fields_iterator()
+{
+    let mut reg = DebugReg::default();
+    reg.set_enable(1);
+    reg.set_rx_mode(0x2a);
+
+    let fields: Vec<_> = reg.fields().collect();
+    assert_eq!(fields, vec![("enable", 1), ("link_up", 0), ("rx_mode", 0x2a), ("full", 0)]);
+
+    // Fields of an msb bitfield are iterated in declaration order as well
+    let mut msb = MsbDynReg::default();
+    msb.set_opcode(0x3f);
+    assert_eq!(msb.fields().collect::<Vec<_>>(), vec![("opcode", 0x3f), ("operand", 0)]);
+}
+
+#[bitf(u8, dynamic)]
+struct CmdReg
+{
+    #[access(wo)]
+    command_4:      (),
+    #[access(ro)]
+    status_4:       (),
+}
+
+#[test]
+fn dynamic_policies()
+{
+    let mut reg = CmdReg { raw: 0x5a };
+
+    // Write-only fields cannot be read through the enum
+    assert!(CmdReg::field_by_name("command").is_none());
+    assert_eq!(reg.fields().collect::<Vec<_>>(), vec![("status", 0x5)]);
+    assert_eq!(reg.get(CmdRegField::Status), 0x5);
+
+    // Read-only fields cannot be written through it
+    assert!(!reg.set(CmdRegField::Status, 0x3));
+    assert_eq!(reg.raw, 0x5a);
+}
+
+#[bitf(u16, msb, pp)]
+struct DiffBitf
+{
+    opcode_6:       (),
+    _reserved_2:    (),
+    operand_8:      (),
+}
+
+#[test]
+fn field_diff()
+{
+    let mut before = DebugReg::default();
+    before.set_rx_mode(3);
+    let mut after = DebugReg { raw: before.raw };
+    after.set_enable(1);
+    after.set_full(0x80);
+
+    assert_eq!(before.diff(&after).collect::<Vec<_>>(), vec![("enable", 0, 1), ("full", 0, 0x80)]);
+    assert_eq!(before.diff(&before).count(), 0);
+
+    let mut a = DiffBitf::default();
+    a.set_opcode(0b101010);
+    let mut b = DiffBitf { raw: a.raw };
+    b.set_operand(0xf0);
+    b.raw |= 0b11 << 8;
+
+    // The change of the reserved bits is not marked
+    let expected = ["16       10   8          0",
+                    "┌────────┬────┬──────────┐",
+                    "│ 101010 │ rr │ 00000000 │",
+                    "├────────┼────┼──────────┤",
+                    "│ 101010 │ rr │ 11110000 │",
+                    "└────────┴────┴──────────┘",
+                    "                ^^^^^^^^",
+                    ""];
+    assert_eq!(a.pprint_render(Some(&b)), expected.join("\n"));
+    a.pprint_diff(&b);
+}
+
+#[bitf(u16, msb, pp, assert)]
+struct AssertBitf
+{
+    opcode_6:       (),
+    _reserved_2:    (),
+    operand_8:      (),
+}
+
+#[bitf(u8, assert)]
+struct PlainAssertBitf
+{
+    low_4:          (),
+    high_4:         (),
+}
+
+#[test]
+fn assert_macro()
+{
+    let mut a = AssertBitf::default();
+    a.set_opcode(0x2a);
+    let mut b = AssertBitf { raw: a.raw };
+
+    // Reserved bits are ignored
+    b.raw |= 0b11 << 8;
+    assert!(a.mismatch_report(&b).is_none());
+    bitf::assert_bitf_eq!(a, b);
+    bitf::assert_bitf_eq!(a, b, "after {} writes", 1);
+
+    b.set_operand(0xf0);
+    let report = a.mismatch_report(&b).unwrap().to_string();
+    assert!(report.starts_with("field    expected  actual\noperand      0xf0     0x0\n\n16"));
+    assert!(report.contains("│ 101010 │ rr │ 11110000 │\n├────────┼────┼──────────┤\n│ 101010 │ rr │ 00000000 │"));
+
+    let err = std::panic::catch_unwind(|| bitf::assert_bitf_eq!(a, b, "snapshot {}", 3)).unwrap_err();
+    let msg = err.downcast_ref::<String>().unwrap();
+    assert!(msg.starts_with("assertion `actual == expected` failed: snapshot 3\nfield "));
+
+    // Without the diagrams, the report is only the table
+    let actual = PlainAssertBitf { raw: 0x1f };
+    let expected = PlainAssertBitf { raw: 0x2e };
+    let report = actual.mismatch_report(&expected).unwrap();
+    assert_eq!(report.to_string(), "field  expected  actual\nlow         0xe     0xf\nhigh        0x2     0x1\n");
+    let err = std::panic::catch_unwind(|| bitf::assert_bitf_eq!(actual, expected)).unwrap_err();
+    assert_eq!(err.downcast_ref::<String>().unwrap(), &format!("assertion `actual == expected` failed\n{}", report));
+}
+
+#[bitf(u32, msb, pattern)]
+struct Insn
+{
+    opcode_6:       (),
+    rs_5:           (),
+    rt_5:           (),
+    imm_16:         (),
+}
+
+const JUMP: (u32, u32) = bitf::bitf_pattern!(Insn, "0000_10xx_xxxx_xxxx_xxxx_xxxx_xxxx_xxxx");
+const ADDI_R0: InsnPattern = Insn::pattern().opcode(0b001000).rs(0);
+
+#[bitf(u8, atomic, pattern)]
+struct AtomicFlags
+{
+    ready_1:        (),
+    code_7:         (),
+}
+
+#[test]
+fn pattern_matching()
+{
+    let jump = Insn { raw: 0x0800_1234 };
+    assert!(jump.matches(JUMP.0, JUMP.1));
+    assert!(!jump.matches_pattern(ADDI_R0));
+    assert_eq!(JUMP, (0x0800_0000, 0xfc00_0000));
+
+    let mut addi = Insn::default();
+    addi.set_opcode(0b001000);
+    addi.set_rt(3);
+    addi.set_imm(42);
+    assert!(addi.matches_pattern(ADDI_R0));
+    assert!(!addi.matches(JUMP.0, JUMP.1));
+    assert_eq!(ADDI_R0, InsnPattern { value: 0x2000_0000, mask: 0xffe0_0000 });
+
+    const { assert!(Insn { raw: 0x0bff_ffff }.matches(JUMP.0, JUMP.1)) };
+
+    // Patterns built at runtime
+    assert_eq!(Insn::from_pattern("1xxxxxxx xxxxxxxx xxxxxxxx xxxxxxx0"), (0x8000_0000, 0x8000_0001));
+    assert!(std::panic::catch_unwind(|| Insn::from_pattern("10x")).is_err());
+    assert!(std::panic::catch_unwind(|| Insn::pattern().rs(32)).is_err());
+
+    let flags = AtomicFlags::default();
+    flags.set_code(0x7f);
+    assert!(flags.matches_pattern(AtomicFlags::pattern().code(0x7f)));
+    assert!(flags.matches(0xfe, 0xfe));
+    assert!(!flags.matches(AtomicFlags::from_pattern("xxxx_xxx1").0, 1));
+}
+
+#[bitf(u32, pattern)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct RType
+{
+    opcode_7:       (),
+    rd_5:           (),
+    funct3_3:       (),
+    rs1_5:          (),
+    rs2_5:          (),
+    funct7_7:       (),
+}
+
+#[bitf(u32, pattern)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct IType
+{
+    opcode_7:       (),
+    rd_5:           (),
+    funct3_3:       (),
+    rs1_5:          (),
+    imm_12:         (),
+}
+
+bitf::bitf_decoder!
+{
+    #[derive(Debug, PartialEq)]
+    enum Rv32: u32
+    {
+        /// Register-register operations
+        Op(RType) = { opcode: 0b0110011 },
+        /// Immediate operations, except shifts
+        OpImm(IType) = { opcode: 0b0010011 },
+        Load(IType) = { opcode: 0b0000011, funct3: 0b010 },
+    }
+}
+
+#[test]
+fn decoder_dispatch()
+{
+    // addi x1, x2, 42
+    let addi = 0x02a1_0093;
+    match Rv32::decode(addi)
+    {
+        Some(Rv32::OpImm(i)) =>
+        {
+            assert_eq!(i.rd(), 1);
+            assert_eq!(i.rs1(), 2);
+            assert_eq!(i.imm(), 42);
+        },
+        other => panic!("unexpected decoding {:?}", other),
+    }
+    assert_eq!(Rv32::decode(addi).unwrap().encode(), addi);
+
+    // add x3, x1, x2
+    let add = 0x0020_81b3;
+    assert!(matches!(Rv32::decode(add), Some(Rv32::Op(r)) if r.rd() == 3 && r.rs2() == 2));
+
+    // lw, then lb which is not part of the decoder
+    assert!(matches!(Rv32::decode(0x0001_2083), Some(Rv32::Load(_))));
+    assert_eq!(Rv32::decode(0x0001_0083), None);
+}
+
+#[bitf(u16, pp)]
+#[derive(Clone, Copy)]
+struct SerialCtrl
+{
+    mode_2:         (),
+    #[variant(Uart, mode = 0)]
+    baud_div_12:    (),
+    #[variant(Spi, mode = 1)]
+    spi_clk_4:      (),
+    #[variant(Spi, mode = 1)]
+    cpol_1:         (),
+    enable_1:       (),
+    _reserved_1:    (),
+}
+
+#[test]
+fn variant_layouts()
+{
+    let mut ctrl = SerialCtrl::default();
+    ctrl.set_baud_div(0x123);
+    ctrl.set_enable(1);
+    assert_eq!(ctrl.raw, (1 << 14) | (0x123 << 2));
+
+    match ctrl.variant()
+    {
+        Some(SerialCtrlVariant::Uart(uart)) => assert_eq!(uart.baud_div(), 0x123),
+        _ => panic!("expected the Uart variant"),
+    }
+
+    // The fields of the variants share the same bits
+    ctrl.set_mode(1);
+    match ctrl.variant()
+    {
+        Some(SerialCtrlVariant::Spi(spi)) =>
+        {
+            assert_eq!(spi.spi_clk(), 0x3);
+            assert_eq!(spi.cpol(), 0);
+        },
+        _ => panic!("expected the Spi variant"),
+    }
+    assert_eq!(ctrl.enable(), 1);
+
+    ctrl.set_mode(2);
+    assert!(ctrl.variant().is_none());
+}
+
+#[bitf(u16)]
+struct PhyCtrl
+{
+    kind_4:         (),
+    #[variant(Copper, kind = 0x2)]
+    speed_2:        (),
+    #[variant(Copper, kind = 0b10)]
+    duplex_1:       (),
+    #[variant(Fiber, kind = 3u8)]
+    lanes_3:        (),
+    _reserved_9:    (),
+}
+
+#[bitf(u16, serde, update)]
+struct LinkCtrl
+{
+    mode_2:         (),
+    #[variant(Uart, mode = 0)]
+    baud_12:        (),
+    #[variant(Spi, mode = 1)]
+    clk_4:          (),
+    #[variant(Spi, mode = 1)]
+    cpol_1:         (),
+    enable_1:       (),
+    _reserved_1:    (),
+}
+
+#[test]
+fn variant_serde()
+{
+    let mut ctrl = LinkCtrl::default();
+    ctrl.set_fields(1, 1);
+    ctrl.update(|w| { w.set_clk(0x3).set_cpol(1); });
+    assert_eq!(ctrl.raw, (1 << 14) | (1 << 6) | (0x3 << 2) | 1);
+
+    // Only the fields of the selected variant are represented
+    let json = serde_json::to_string(&ctrl).unwrap();
+    assert_eq!(json, r#"{"mode":1,"clk":3,"cpol":1,"enable":1}"#);
+    assert_eq!(serde_json::from_str::<LinkCtrl>(&json).unwrap().raw, ctrl.raw);
+
+    let uart: LinkCtrl = serde_json::from_str(r#"{"mode":0,"baud":291,"enable":0}"#).unwrap();
+    assert_eq!(uart.baud(), 291);
+
+    let err = |s: &str| serde_json::from_str::<LinkCtrl>(s).err().unwrap().to_string();
+    assert!(err(r#"{"mode":0,"enable":1}"#).contains("missing field `baud`"));
+    assert!(err(r#"{"mode":0,"baud":5,"clk":1,"enable":1}"#).contains("field `clk` is not part of the variant selected by `mode`"));
+}
+
+#[test]
+fn variant_tag_values()
+{
+    let mut ctrl = PhyCtrl::default();
+    ctrl.set_kind(2);
+    ctrl.set_speed(0b10);
+    ctrl.set_duplex(1);
+
+    // The values of the tag are the same in different bases
+    match ctrl.variant()
+    {
+        Some(PhyCtrlVariant::Copper(copper)) =>
+        {
+            assert_eq!(copper.speed(), 0b10);
+            assert_eq!(copper.duplex(), 1);
+        },
+        _ => panic!("expected the Copper variant"),
+    }
+
+    // The suffix of the value does not constrain the type of the tag
+    ctrl.set_kind(3);
+    assert!(matches!(ctrl.variant(), Some(PhyCtrlVariant::Fiber(fiber)) if fiber.lanes() == 0b110));
+}
+
+#[bitf(u32, msb, pp)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct DmaAddr
+{
+    channel_8:      (),
+    addr_16:        (),
+    #[overlay]
+    addr_hi_8:      (),
+    #[overlay]
+    addr_lo_8:      (),
+    _reserved_8:    (),
+}
+
+#[bitf(u16)]
+struct OverlayLsb
+{
+    word_12:        (),
+    #[overlay]
+    low_4:          (),
+    #[overlay]
+    mid_4:          (),
+    flag_1:         (),
+    _reserved_3:    (),
+}
+
+#[test]
+fn overlay_fields()
+{
+    let mut dma = DmaAddr::default();
+    dma.set_channel(0x7f);
+    dma.set_addr(0xbeef);
+    assert_eq!(dma.raw, 0x7fbe_ef00);
+    assert_eq!(dma.addr_hi(), 0xbe);
+    assert_eq!(dma.addr_lo(), 0xef);
+
+    // Writing an overlay only changes its bits of the shared field
+    dma.set_addr_lo(0x01);
+    assert_eq!(dma.addr(), 0xbe01);
+    assert_eq!(dma.channel(), 0x7f);
+
+    let mut reg = OverlayLsb::default();
+    reg.set_word(0xabc);
+    reg.set_flag(1);
+    assert_eq!(reg.low(), 0xc);
+    assert_eq!(reg.mid(), 0xb);
+    reg.set_mid(0);
+    assert_eq!(reg.raw, 0x1a0c);
+}
+
+#[bitf(u32, msb, serde, update, dynamic, derive(Debug, Display, FromStr, PartialEq))]
+struct DmaDesc
+{
+    channel_8:      (),
+    addr_16:        (),
+    #[overlay]
+    addr_hi_8:      (),
+    #[overlay]
+    addr_lo_8:      (),
+    _reserved_8:    (),
+}
+
+#[test]
+fn overlay_representations()
+{
+    // Overlays are left out, as they repeat the bits of their host
+    let mut desc = DmaDesc::default();
+    desc.set_fields(0x7f, 0xbeef);
+    assert_eq!(desc.raw, 0x7fbe_ef00);
+
+    assert_eq!(format!("{:?}", desc), "DmaDesc { channel: 127, addr: 48879 }");
+    assert_eq!(desc.to_string(), "channel=127,addr=48879");
+    assert_eq!("channel=127,addr=0xbeef".parse::<DmaDesc>().unwrap(), desc);
+    assert!(matches!("addr_hi=1".parse::<DmaDesc>(), Err(DmaDescParseError::UnknownField(_))));
+
+    let json = serde_json::to_string(&desc).unwrap();
+    assert_eq!(json, r#"{"channel":127,"addr":48879}"#);
+    assert_eq!(serde_json::from_str::<DmaDesc>(r#"{"channel":127,"addr":48879}"#).unwrap(), desc);
+    let err = serde_json::from_str::<DmaDesc>(r#"{"channel":127,"addr":1,"addr_hi":2}"#).unwrap_err().to_string();
+    assert!(err.contains("unknown field `addr_hi`"));
+
+    assert_eq!(desc.fields().collect::<Vec<_>>(), vec![("channel", 0x7f), ("addr", 0xbeef)]);
+    let mut other = DmaDesc { raw: desc.raw };
+    other.set_addr_lo(0x01);
+    assert_eq!(desc.diff(&other).collect::<Vec<_>>(), vec![("addr", 0xbeef, 0xbe01)]);
+
+    // The overlays remain accessible by name
+    assert_eq!(DmaDesc::field_by_name("addr_hi"), Some(DmaDescField::AddrHi));
+    assert_eq!(desc.get(DmaDescField::AddrHi), 0xbe);
+    desc.update(|w| { w.set_addr_lo(0x01); });
+    assert_eq!(desc, other);
+}